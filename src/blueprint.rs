@@ -0,0 +1,408 @@
+use std::{error::Error, fs, path::Path};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crosshair::CrosshairTarget,
+    damage::{ModuleArmor, ModuleHealth},
+    docking::DockingPort,
+    fuel::FuelTank,
+    input::Action,
+    module::{
+        spawn_ship_module, ModuleColor, ModuleDefinitionId, ModuleEngineTag, ModuleMass,
+        ModuleMaterial, ModuleShape, ModuleTag, Size,
+    },
+    module_definition::{
+        BatteryDefinition, DockingPortDefinition, EngineDefinition, FuelTankDefinition,
+        GeneratorDefinition, ModuleDefinition, ModuleRegistry, PowerDrawDefinition,
+        RotorDefinition, DEFAULT_HEALTH,
+    },
+    power::{Battery, Generator, PowerConsumer},
+    rotor::Rotor,
+    ship::{spawn_ship, ModuleJoint, ShipTag, DEFAULT_BREAK_FORCE, DEFAULT_BREAK_TORQUE},
+    ship_grid::ModulePorts,
+    simulation::SimulationInput,
+    IS_HEADLESS,
+};
+
+pub const BLUEPRINT_DIR: &str = "blueprints";
+/// Name of the blueprint the save and load actions use.
+const QUICK_BLUEPRINT: &str = "quicksave";
+/// How far in front of the camera loaded blueprints are spawned.
+const BLUEPRINT_SPAWN_DISTANCE: f32 = 10.0;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum BlueprintModuleSource {
+    /// Spawned from the `ModuleRegistry` by id
+    Definition(String),
+    /// Spawned from the recorded properties
+    Custom(Box<ModuleDefinition>),
+}
+
+impl BlueprintModuleSource {
+    /// How a module with these components can be spawned again.
+    pub fn from_components(components: &BlueprintModuleComponents) -> Self {
+        let (
+            _,
+            size,
+            shape,
+            color,
+            ports,
+            mass,
+            material,
+            (engine, fuel_tank, generator, battery, power_consumer, docking_port, rotor),
+            health,
+            armor,
+            definition_id,
+            _,
+        ) = components;
+        match definition_id {
+            Some(id) => BlueprintModuleSource::Definition(id.0.clone()),
+            None => BlueprintModuleSource::Custom(Box::new(ModuleDefinition {
+                name: String::new(),
+                shape: **shape,
+                size: size.0,
+                // Tanks are spawned full, so only their dry mass is recorded
+                mass: match fuel_tank {
+                    Some(fuel_tank) => Some(fuel_tank.dry_mass),
+                    None => mass.map(|mass| mass.0),
+                },
+                density: None,
+                material: material.copied(),
+                color: color.0,
+                ports: ports.0.clone(),
+                engine: engine.map(|engine| EngineDefinition {
+                    thrust: engine.thrust,
+                    exhaust_velocity: engine.exhaust_velocity,
+                }),
+                fuel_tank: fuel_tank.map(|fuel_tank| FuelTankDefinition {
+                    capacity: fuel_tank.capacity,
+                }),
+                generator: generator.map(|generator| GeneratorDefinition {
+                    output: generator.output,
+                }),
+                battery: battery.map(|battery| BatteryDefinition {
+                    capacity: battery.capacity,
+                    max_rate: battery.max_rate,
+                }),
+                power_draw: power_consumer.map(|consumer| PowerDrawDefinition {
+                    draw: consumer.draw,
+                    priority: consumer.priority,
+                }),
+                docking_port: docking_port.map(|docking_port| DockingPortDefinition {
+                    face: docking_port.face,
+                    kind: docking_port.kind,
+                }),
+                rotor: rotor.map(|rotor| RotorDefinition {
+                    axis: rotor.axis,
+                    limits: rotor.limits,
+                    max_torque: rotor.max_torque,
+                    speed: rotor.speed,
+                    target: rotor.target,
+                }),
+                health: health.map_or(DEFAULT_HEALTH, |health| health.max),
+                armor: armor.map(|armor| armor.0.clone()).unwrap_or_default(),
+            })),
+        }
+    }
+
+    /// Spawns the module as part of `ship` with `overrides` applied, if its definition is
+    /// known.
+    pub fn spawn(
+        &self,
+        commands: &mut Commands,
+        meshes: &mut ResMut<Assets<Mesh>>,
+        materials: &mut ResMut<Assets<StandardMaterial>>,
+        registry: &ModuleRegistry,
+        ship: Entity,
+        transform: Transform,
+        overrides: &ModuleOverrides,
+        is_headless: bool,
+    ) -> Option<Entity> {
+        let (id, definition) = match self {
+            BlueprintModuleSource::Definition(id) => match registry.get(id) {
+                Some(definition) => (Some(id), definition),
+                None => {
+                    println!("Unknown module definition: {}", id);
+                    return None;
+                }
+            },
+            BlueprintModuleSource::Custom(definition) => (None, &**definition),
+        };
+
+        let definition = ModuleDefinition {
+            color: overrides.color.unwrap_or(definition.color),
+            ..definition.clone()
+        };
+        let module = spawn_ship_module(
+            commands,
+            meshes,
+            materials,
+            ship,
+            &definition,
+            transform,
+            is_headless,
+        );
+        if let Some(id) = id {
+            commands
+                .entity(module)
+                .insert(ModuleDefinitionId(id.clone()));
+        }
+        if let (Some(fuel), Some(tank)) = (overrides.fuel, &definition.fuel_tank) {
+            let fuel = fuel.clamp(0.0, tank.capacity);
+            let dry_mass = definition.resolved_mass();
+            commands.entity(module).insert((
+                FuelTank {
+                    capacity: tank.capacity,
+                    fuel,
+                    dry_mass,
+                },
+                ModuleMass(dry_mass + fuel),
+            ));
+        }
+        Some(module)
+    }
+}
+
+/// State of a module that differs between instances of the same definition.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModuleOverrides {
+    #[serde(default)]
+    pub color: Option<Color>,
+    /// Propellant left in the tank, in kilograms
+    #[serde(default)]
+    pub fuel: Option<f32>,
+}
+
+impl ModuleOverrides {
+    pub fn from_components(components: &BlueprintModuleComponents) -> Self {
+        let (_, _, _, color, _, _, _, (_, fuel_tank, ..), ..) = components;
+        ModuleOverrides {
+            color: Some(color.0),
+            fuel: fuel_tank.map(|fuel_tank| fuel_tank.fuel),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BlueprintModule {
+    pub source: BlueprintModuleSource,
+    /// Transform relative to the ship
+    pub transform: Transform,
+    #[serde(default)]
+    pub overrides: ModuleOverrides,
+}
+
+/// A `ModuleJoint` between two modules, referenced by their index in the blueprint.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct BlueprintJoint {
+    pub parent: usize,
+    pub child: usize,
+    pub anchor: Vec3,
+    #[serde(default = "default_break_force")]
+    pub break_force: f32,
+    #[serde(default = "default_break_torque")]
+    pub break_torque: f32,
+}
+
+fn default_break_force() -> f32 {
+    DEFAULT_BREAK_FORCE
+}
+
+fn default_break_torque() -> f32 {
+    DEFAULT_BREAK_TORQUE
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ShipBlueprint {
+    pub modules: Vec<BlueprintModule>,
+    pub joints: Vec<BlueprintJoint>,
+}
+
+/// The optional parts that give a module its function.
+pub type ModuleFunctionComponents<'a> = (
+    Option<&'a ModuleEngineTag>,
+    Option<&'a FuelTank>,
+    Option<&'a Generator>,
+    Option<&'a Battery>,
+    Option<&'a PowerConsumer>,
+    Option<&'a DockingPort>,
+    Option<&'a Rotor>,
+);
+
+pub type BlueprintModuleComponents<'a> = (
+    &'a GlobalTransform,
+    &'a Size,
+    &'a ModuleShape,
+    &'a ModuleColor,
+    &'a ModulePorts,
+    Option<&'a ModuleMass>,
+    Option<&'a ModuleMaterial>,
+    ModuleFunctionComponents<'a>,
+    Option<&'a ModuleHealth>,
+    Option<&'a ModuleArmor>,
+    Option<&'a ModuleDefinitionId>,
+    Option<&'a ModuleJoint>,
+);
+
+impl ShipBlueprint {
+    /// Records the modules of `ship` and the joints between them. Transforms are stored
+    /// relative to the first module, so this works for both merged and jointed ships.
+    pub fn capture(
+        ship: Entity,
+        ship_query: &Query<&Children, With<ShipTag>>,
+        module_query: &Query<BlueprintModuleComponents, With<ModuleTag>>,
+    ) -> Option<Self> {
+        let children = ship_query.get(ship).ok()?;
+        let modules: Vec<(Entity, BlueprintModuleComponents)> = children
+            .iter()
+            .filter_map(|child| module_query.get(*child).ok().map(|m| (*child, m)))
+            .collect();
+        let (_, (root_global, ..)) = modules.first()?;
+
+        let indices: HashMap<Entity, usize> = modules
+            .iter()
+            .enumerate()
+            .map(|(index, (entity, _))| (*entity, index))
+            .collect();
+
+        let mut blueprint = ShipBlueprint::default();
+        for (index, (_, components)) in modules.iter().enumerate() {
+            let (global, .., joint) = components;
+            blueprint.modules.push(BlueprintModule {
+                source: BlueprintModuleSource::from_components(components),
+                transform: global.reparented_to(root_global),
+                overrides: ModuleOverrides::from_components(components),
+            });
+
+            if let Some(joint) = joint {
+                if let Some(parent) = indices.get(&joint.parent) {
+                    blueprint.joints.push(BlueprintJoint {
+                        parent: *parent,
+                        child: index,
+                        anchor: joint.anchor,
+                        break_force: joint.break_force,
+                        break_torque: joint.break_torque,
+                    });
+                }
+            }
+        }
+
+        Some(blueprint)
+    }
+
+    /// Spawns the blueprint as a new merged ship. Modules with an unknown definition id
+    /// are skipped, along with their joints.
+    pub fn spawn(
+        &self,
+        commands: &mut Commands,
+        meshes: &mut ResMut<Assets<Mesh>>,
+        materials: &mut ResMut<Assets<StandardMaterial>>,
+        registry: &ModuleRegistry,
+        transform: Transform,
+        velocity: Velocity,
+        is_headless: bool,
+    ) -> Entity {
+        let ship = spawn_ship(commands, transform, velocity);
+
+        let modules: Vec<Option<Entity>> = self
+            .modules
+            .iter()
+            .map(|module| {
+                module.source.spawn(
+                    commands,
+                    meshes,
+                    materials,
+                    registry,
+                    ship,
+                    module.transform,
+                    &module.overrides,
+                    is_headless,
+                )
+            })
+            .collect();
+
+        for joint in self.joints.iter() {
+            if let (Some(Some(parent)), Some(Some(child))) =
+                (modules.get(joint.parent), modules.get(joint.child))
+            {
+                commands.entity(*child).insert(ModuleJoint {
+                    parent: *parent,
+                    anchor: joint.anchor,
+                    break_force: joint.break_force,
+                    break_torque: joint.break_torque,
+                });
+            }
+        }
+
+        ship
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        if let Some(directory) = path.as_ref().parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(ron::from_str(&text)?)
+    }
+}
+
+/// Path of the blueprint file called `name` in [`BLUEPRINT_DIR`].
+pub fn blueprint_path(name: &str) -> std::path::PathBuf {
+    Path::new(BLUEPRINT_DIR).join(format!("{}.ron", name))
+}
+
+/// Saves the ship under the crosshair to the quick save blueprint, or spawns that blueprint in
+/// front of the camera.
+pub fn blueprint_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    registry: Res<ModuleRegistry>,
+    crosshair_target: Res<CrosshairTarget>,
+    input: Res<SimulationInput>,
+    ship_query: Query<&Children, With<ShipTag>>,
+    module_query: Query<BlueprintModuleComponents, With<ModuleTag>>,
+) {
+    if input.menu_open() {
+        return;
+    }
+    let camera = input.camera();
+
+    if input.just_pressed(Action::SaveBlueprint) {
+        let Some(ship) = crosshair_target.ship else {
+            return;
+        };
+        let Some(blueprint) = ShipBlueprint::capture(ship, &ship_query, &module_query) else {
+            return;
+        };
+        if let Err(e) = blueprint.save(blueprint_path(QUICK_BLUEPRINT)) {
+            println!("Failed to save blueprint {}: {}", QUICK_BLUEPRINT, e);
+        }
+    } else if input.just_pressed(Action::LoadBlueprint) {
+        match ShipBlueprint::load(blueprint_path(QUICK_BLUEPRINT)) {
+            Ok(blueprint) => {
+                blueprint.spawn(
+                    &mut commands,
+                    &mut meshes,
+                    &mut materials,
+                    &registry,
+                    Transform::from_translation(
+                        camera.translation + camera.forward() * BLUEPRINT_SPAWN_DISTANCE,
+                    ),
+                    Velocity::zero(),
+                    IS_HEADLESS,
+                );
+            }
+            Err(e) => println!("Failed to load blueprint {}: {}", QUICK_BLUEPRINT, e),
+        }
+    }
+}
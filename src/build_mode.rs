@@ -0,0 +1,462 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    blueprint::{BlueprintModuleSource, ModuleOverrides},
+    edit_history::{
+        set_module_color, EditCommand, EditHistory, ModuleSnapshot, SnapshotModuleComponents,
+    },
+    input::Action,
+    module::{module_mesh, ModuleTag, Size},
+    module_definition::{ModuleDefinition, ModuleRegistry},
+    settings::ControlSettings,
+    ship::{ModuleJoint, ShipTag},
+    ship_grid::{
+        cell_at, occupied_cells, plan_placement, Face, GridPlacement, ModulePorts, PlacementTarget,
+        GRID_CELL_SIZE,
+    },
+    simulation::SimulationInput,
+    IS_HEADLESS,
+};
+
+/// How far away from the camera modules can be placed or removed.
+const BUILD_RANGE: f32 = 20.0;
+const GHOST_VALID_COLOR: Color = Color::rgba(0.2, 0.8, 0.2, 0.4);
+const GHOST_INVALID_COLOR: Color = Color::rgba(0.8, 0.2, 0.2, 0.4);
+
+#[derive(Resource, Default)]
+pub struct BuildMode {
+    pub active: bool,
+    /// Id of the module definition that gets placed
+    pub selected: Option<String>,
+    /// Rotation of the placed module relative to its ship
+    pub rotation: Quat,
+    /// The ghost entity and the definition id it was spawned for
+    ghost: Option<(Entity, String)>,
+}
+
+/// Translucent preview of the module that would be placed.
+#[derive(Component)]
+pub struct BuildGhost;
+
+/// The module face under the crosshair.
+struct BuildTarget {
+    ship: Entity,
+    module: Entity,
+    face: Face,
+    /// Frame the ship's grid is in, which differs from the ship transform while jointed
+    grid_frame: GlobalTransform,
+}
+
+pub fn update_build_mode(
+    mut build_mode: ResMut<BuildMode>,
+    input: Res<SimulationInput>,
+    registry: Res<ModuleRegistry>,
+) {
+    if input.menu_open() {
+        build_mode.active = false;
+        return;
+    }
+
+    if input.just_pressed(Action::ToggleBuildMode) {
+        build_mode.active = !build_mode.active;
+    }
+    if !build_mode.active {
+        return;
+    }
+
+    let mut ids: Vec<&String> = registry.ids().collect();
+    ids.sort();
+    let selected_index = build_mode
+        .selected
+        .as_ref()
+        .and_then(|selected| ids.iter().position(|id| *id == selected));
+    if selected_index.is_none() || input.just_pressed(Action::CycleModule) {
+        let next = selected_index.map_or(0, |index| (index + 1) % ids.len());
+        build_mode.selected = ids.get(next).map(|id| id.to_string());
+    }
+
+    if input.just_pressed(Action::RotateModule) {
+        build_mode.rotation = Quat::from_rotation_y(FRAC_PI_2) * build_mode.rotation;
+    }
+}
+
+type BuildModuleComponents<'a> = (
+    &'a GlobalTransform,
+    &'a Size,
+    &'a GridPlacement,
+    &'a ModulePorts,
+    &'a Parent,
+);
+
+fn find_build_target(
+    hit: Entity,
+    intersection: RayIntersection,
+    ship_query: &Query<(&GlobalTransform, &Children), With<ShipTag>>,
+    module_query: &Query<BuildModuleComponents, With<ModuleTag>>,
+) -> Option<BuildTarget> {
+    // Merged ships are hit as a whole, look up the module from the cell that was hit
+    let module = if module_query.contains(hit) {
+        hit
+    } else {
+        let (ship_global, children) = ship_query.get(hit).ok()?;
+        let inside = intersection.point - intersection.normal * GRID_CELL_SIZE * 0.1;
+        let cell = cell_at(ship_global.affine().inverse().transform_point3(inside));
+        children.iter().copied().find(|child| {
+            module_query
+                .get(*child)
+                .is_ok_and(|(_, _, placement, ..)| placement.contains(cell))
+        })?
+    };
+
+    let (module_global, _, placement, _, parent) = module_query.get(module).ok()?;
+    let (_, module_rotation, _) = module_global.to_scale_rotation_translation();
+    Some(BuildTarget {
+        ship: parent.get(),
+        module,
+        face: Face::from_direction(module_rotation.inverse() * intersection.normal),
+        grid_frame: GlobalTransform::from(
+            module_global.compute_matrix() * placement.transform().compute_matrix().inverse(),
+        ),
+    })
+}
+
+/// Whether `module` hangs off `ancestor` through a chain of joints.
+fn is_jointed_to(
+    mut module: Entity,
+    ancestor: Entity,
+    joint_query: &Query<(Entity, &ModuleJoint)>,
+) -> bool {
+    let mut visited = HashSet::new();
+    while let Ok((_, joint)) = joint_query.get(module) {
+        if joint.parent == ancestor {
+            return true;
+        }
+        if !visited.insert(module) {
+            return false;
+        }
+        module = joint.parent;
+    }
+    false
+}
+
+/// Neighbors `module` can be jointed to, with the anchor of the port facing them. Modules
+/// hanging off `module` are skipped, jointing to them would cut it off from the ship.
+fn rejoint_candidates(
+    module: Entity,
+    occupied: &HashMap<IVec3, Entity>,
+    module_query: &Query<BuildModuleComponents, With<ModuleTag>>,
+    joint_query: &Query<(Entity, &ModuleJoint)>,
+) -> Vec<(Entity, Vec3)> {
+    let Ok((_, size, placement, ports, _)) = module_query.get(module) else {
+        return Vec::new();
+    };
+    let transform = placement.transform();
+
+    ports
+        .0
+        .iter()
+        .filter_map(|port| {
+            let anchor = port.local_position(size.0);
+            let port_position = transform.transform_point(anchor);
+            let outward = placement.rotation * port.face.normal();
+            let neighbor =
+                *occupied.get(&cell_at(port_position + outward * GRID_CELL_SIZE / 2.0))?;
+            let (_, neighbor_size, neighbor_placement, neighbor_ports, _) =
+                module_query.get(neighbor).ok()?;
+
+            // The neighbor needs a compatible port right at this one
+            let neighbor_transform = neighbor_placement.transform();
+            let connects = neighbor_ports.0.iter().any(|other| {
+                other.kind.is_compatible(port.kind)
+                    && neighbor_transform
+                        .transform_point(other.local_position(neighbor_size.0))
+                        .abs_diff_eq(port_position, GRID_CELL_SIZE * 0.01)
+            });
+            (connects && neighbor != module && !is_jointed_to(neighbor, module, joint_query))
+                .then_some((neighbor, anchor))
+        })
+        .collect()
+}
+
+/// Where the ghost is shown when the module can't be placed, right outside the face.
+fn ghost_placement(
+    target: &PlacementTarget,
+    face: Face,
+    definition: &ModuleDefinition,
+    rotation: Quat,
+) -> GridPlacement {
+    let outward = target.placement.rotation * face.normal();
+    let face_center = target
+        .placement
+        .transform()
+        .transform_point(face.normal() * target.size / 2.0);
+    let depth = (rotation * definition.size).abs().dot(outward.abs());
+    GridPlacement::snapped(
+        face_center + outward * depth / 2.0,
+        definition.size,
+        rotation,
+    )
+}
+
+fn spawn_ghost(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+    definition: &ModuleDefinition,
+) -> Entity {
+    commands
+        .spawn((
+            BuildGhost,
+            PbrBundle {
+                mesh: meshes.add(module_mesh(definition.shape, definition.size)),
+                material: materials.add(StandardMaterial {
+                    base_color: GHOST_VALID_COLOR,
+                    alpha_mode: AlphaMode::Blend,
+                    ..default()
+                }),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .id()
+}
+
+pub fn build_mode_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut build_mode: ResMut<BuildMode>,
+    registry: Res<ModuleRegistry>,
+    rapier_context: Res<RapierContext>,
+    input: Res<SimulationInput>,
+    ship_query: Query<(&GlobalTransform, &Children), With<ShipTag>>,
+    control_settings: Res<ControlSettings>,
+    mut history: ResMut<EditHistory>,
+    module_query: Query<BuildModuleComponents, With<ModuleTag>>,
+    snapshot_query: Query<SnapshotModuleComponents, With<ModuleTag>>,
+    joint_query: Query<(Entity, &ModuleJoint)>,
+    material_query: Query<&Handle<StandardMaterial>, With<ModuleTag>>,
+    mut ghost_query: Query<
+        (&mut Transform, &mut Visibility, &Handle<StandardMaterial>),
+        (With<BuildGhost>, Without<ModuleTag>),
+    >,
+) {
+    let selected = build_mode
+        .selected
+        .clone()
+        .filter(|_| build_mode.active)
+        .and_then(|id| registry.get(&id).map(|definition| (id, definition)));
+
+    // Respawn the ghost when the selection changes, and remove it outside of build mode
+    let ghost_id = build_mode.ghost.as_ref().map(|(_, id)| id.as_str());
+    if ghost_id != selected.as_ref().map(|(id, _)| id.as_str()) {
+        if let Some((ghost, _)) = build_mode.ghost.take() {
+            commands.entity(ghost).despawn_recursive();
+        }
+        if let Some((id, definition)) = &selected {
+            let ghost = spawn_ghost(&mut commands, &mut meshes, &mut materials, definition);
+            build_mode.ghost = Some((ghost, id.clone()));
+        }
+    }
+    let Some((id, definition)) = selected else {
+        return;
+    };
+    let camera = input.camera();
+
+    let target = rapier_context
+        .cast_ray_and_get_normal(
+            camera.translation,
+            camera.forward(),
+            BUILD_RANGE,
+            true,
+            QueryFilter::default(),
+        )
+        .and_then(|(hit, intersection)| {
+            find_build_target(hit, intersection, &ship_query, &module_query)
+        });
+
+    let placement = target.as_ref().map(|target| {
+        let (_, size, placement, ports, _) = module_query.get(target.module).unwrap();
+        let siblings: Vec<Entity> = ship_query
+            .get(target.ship)
+            .map(|(_, children)| children.iter().copied().collect())
+            .unwrap_or_default();
+        let occupied = occupied_cells(siblings.into_iter().filter_map(|module| {
+            module_query
+                .get(module)
+                .ok()
+                .map(|(_, _, placement, ..)| (module, placement))
+        }));
+        let target_placement = PlacementTarget {
+            module: target.module,
+            placement,
+            size: size.0,
+            ports: &ports.0,
+        };
+        let result = plan_placement(
+            &target_placement,
+            target.face,
+            definition,
+            build_mode.rotation,
+            &occupied,
+        );
+        let grid = match &result {
+            Ok(placement) => placement.grid,
+            Err(_) => ghost_placement(
+                &target_placement,
+                target.face,
+                definition,
+                build_mode.rotation,
+            ),
+        };
+        (grid, result)
+    });
+
+    // Move the ghost onto the target face
+    if let Some((ghost, _)) = &build_mode.ghost {
+        if let Ok((mut transform, mut visibility, material)) = ghost_query.get_mut(*ghost) {
+            match (&target, &placement) {
+                (Some(target), Some((grid, result))) => {
+                    *transform = Transform::from_matrix(
+                        target.grid_frame.compute_matrix() * grid.transform().compute_matrix(),
+                    );
+                    *visibility = Visibility::Inherited;
+                    if let Some(material) = materials.get_mut(material) {
+                        material.base_color = match result {
+                            Ok(_) => GHOST_VALID_COLOR,
+                            Err(_) => GHOST_INVALID_COLOR,
+                        };
+                    }
+                }
+                _ => *visibility = Visibility::Hidden,
+            }
+        }
+    }
+
+    let Some(target) = target else {
+        return;
+    };
+
+    let Ok((ship_global, _)) = ship_query.get(target.ship) else {
+        return;
+    };
+    let depth = control_settings.undo_history_depth;
+
+    if input.just_pressed(Action::PlaceModule) {
+        match placement.map(|(_, result)| result) {
+            Some(Ok(placement)) => {
+                let transform = GlobalTransform::from(
+                    target.grid_frame.compute_matrix()
+                        * placement.grid.transform().compute_matrix(),
+                )
+                .reparented_to(ship_global);
+                if let Some(module) = registry.spawn_in_ship(
+                    &id,
+                    &mut commands,
+                    &mut meshes,
+                    &mut materials,
+                    target.ship,
+                    transform,
+                    IS_HEADLESS,
+                ) {
+                    commands
+                        .entity(module)
+                        .insert((placement.grid, placement.joint));
+                    history.push(
+                        EditCommand::Place(ModuleSnapshot {
+                            module,
+                            ship: target.ship,
+                            ship_transform: ship_global.compute_transform(),
+                            source: BlueprintModuleSource::Definition(id),
+                            overrides: ModuleOverrides::default(),
+                            transform,
+                            placement: Some(placement.grid),
+                            joint: Some(placement.joint),
+                            dependents: Vec::new(),
+                        }),
+                        depth,
+                    );
+                }
+            }
+            Some(Err(error)) => println!("Can't place module: {}", error),
+            None => {}
+        }
+    } else if input.just_pressed(Action::RemoveModule) {
+        if let Some(snapshot) =
+            ModuleSnapshot::capture(target.module, ship_global, &snapshot_query, &joint_query)
+        {
+            history.push(EditCommand::Remove(snapshot), depth);
+        }
+        commands.entity(target.module).despawn_recursive();
+    } else if input.just_pressed(Action::PaintModule) {
+        // Paints the targeted module in the color of the selected module
+        let Ok((.., (_, _, _, color, ..))) = snapshot_query.get(target.module) else {
+            return;
+        };
+        if color.0 != definition.color {
+            history.push(
+                EditCommand::Recolor {
+                    module: target.module,
+                    from: color.0,
+                    to: definition.color,
+                },
+                depth,
+            );
+            set_module_color(
+                &mut commands,
+                target.module,
+                definition.color,
+                material_query.get(target.module).ok(),
+                &mut materials,
+            );
+        }
+    } else if input.just_pressed(Action::RejointModule) {
+        // Cycles the targeted module's joint through the neighbors it has ports towards
+        let siblings: Vec<Entity> = ship_query
+            .get(target.ship)
+            .map(|(_, children)| children.iter().copied().collect())
+            .unwrap_or_default();
+        let occupied = occupied_cells(siblings.into_iter().filter_map(|module| {
+            module_query
+                .get(module)
+                .ok()
+                .map(|(_, _, placement, ..)| (module, placement))
+        }));
+        let candidates = rejoint_candidates(target.module, &occupied, &module_query, &joint_query);
+        let current = joint_query.get(target.module).ok().map(|(_, joint)| *joint);
+        let next = candidates
+            .iter()
+            .position(|(parent, _)| Some(*parent) == current.map(|joint| joint.parent))
+            .map_or(0, |index| index + 1);
+        let Some((parent, anchor)) = candidates.get(next % candidates.len().max(1)).copied() else {
+            return;
+        };
+        if current.map(|joint| joint.parent) == Some(parent) {
+            return;
+        }
+
+        let joint = match current {
+            Some(current) => ModuleJoint {
+                parent,
+                anchor,
+                ..current
+            },
+            None => ModuleJoint::new(parent, anchor),
+        };
+        history.push(
+            EditCommand::Rejoint {
+                module: target.module,
+                from: current,
+                to: Some(joint),
+            },
+            depth,
+        );
+        commands.entity(target.module).insert(joint);
+    }
+}
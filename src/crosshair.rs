@@ -0,0 +1,47 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{module::ModuleTag, ship::ShipTag, simulation::SimulationInput};
+
+/// How far away from the camera the crosshair picks things up.
+const CROSSHAIR_RANGE: f32 = 100.0;
+
+/// What the camera is looking at.
+#[derive(Resource, Default)]
+pub struct CrosshairTarget {
+    pub ship: Option<Entity>,
+    /// Only set when the hit module has a collider of its own
+    pub module: Option<Entity>,
+    /// Where the ray hit, in world space
+    pub point: Vec3,
+}
+
+pub fn update_crosshair_target(
+    mut crosshair_target: ResMut<CrosshairTarget>,
+    rapier_context: Res<RapierContext>,
+    input: Res<SimulationInput>,
+    ship_query: Query<(), With<ShipTag>>,
+    module_query: Query<&Parent, With<ModuleTag>>,
+) {
+    let camera = input.camera();
+    *crosshair_target = match rapier_context.cast_ray(
+        camera.translation,
+        camera.forward(),
+        CROSSHAIR_RANGE,
+        true,
+        QueryFilter::default(),
+    ) {
+        Some((entity, toi)) => {
+            let module = module_query.get(entity).ok();
+            CrosshairTarget {
+                ship: match module {
+                    Some(parent) => Some(parent.get()).filter(|ship| ship_query.contains(*ship)),
+                    None => Some(entity).filter(|ship| ship_query.contains(*ship)),
+                },
+                module: module.map(|_| entity),
+                point: camera.translation + camera.forward() * toi,
+            }
+        }
+        None => CrosshairTarget::default(),
+    };
+}
@@ -0,0 +1,225 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    module::{module_mass, ModuleColor, ModuleMass, ModuleTag, Size},
+    simulation::SimulationRng,
+    IS_HEADLESS,
+};
+
+/// Pieces of debris a destroyed module breaks into along each axis.
+const DEBRIS_DIVISIONS: i32 = 2;
+/// Average speed at which debris flies away from the destroyed module's center.
+const DEBRIS_SPEED: f32 = 2.0;
+/// Highest spin in radians per second that debris gets on top of the module's.
+const DEBRIS_SPIN: f32 = 1.0;
+/// Seconds until debris is removed.
+const DEBRIS_LIFETIME: f32 = 10.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DamageType {
+    Kinetic,
+    Thermal,
+    Explosive,
+    Electrical,
+}
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct ModuleHealth {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl ModuleHealth {
+    pub fn new(max: f32) -> Self {
+        ModuleHealth { current: max, max }
+    }
+}
+
+/// Damage taken off every hit, per damage type.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct ModuleArmor(pub HashMap<DamageType, f32>);
+
+impl ModuleArmor {
+    /// Damage left over after the armor absorbed its share.
+    pub fn reduce(&self, amount: f32, kind: DamageType) -> f32 {
+        (amount - self.0.get(&kind).copied().unwrap_or(0.0)).max(0.0)
+    }
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub amount: f32,
+    pub kind: DamageType,
+    /// The entity that caused the damage, if any
+    pub source: Option<Entity>,
+}
+
+#[derive(Component)]
+pub struct Debris {
+    /// Seconds left until the debris is removed
+    pub lifetime: f32,
+}
+
+type DamagedModuleComponents<'a> = (
+    &'a mut ModuleHealth,
+    Option<&'a ModuleArmor>,
+    &'a GlobalTransform,
+    &'a Size,
+    &'a ModuleColor,
+    Option<&'a ModuleMass>,
+    Option<&'a Velocity>,
+    Option<&'a Parent>,
+);
+
+/// Applies damage to modules and destroys the ones that run out of health. Removing a
+/// module from its ship is enough for the ship graph to split off disconnected pieces.
+pub fn apply_damage(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut module_query: Query<DamagedModuleComponents, With<ModuleTag>>,
+    body_query: Query<(&Velocity, &GlobalTransform, &ReadMassProperties)>,
+    source_query: Query<&GlobalTransform>,
+    mut rng: ResMut<SimulationRng>,
+) {
+    for event in damage_events.read() {
+        let Ok((mut health, armor, global, size, color, mass, velocity, parent)) =
+            module_query.get_mut(event.target)
+        else {
+            continue;
+        };
+        // Already destroyed by an earlier event this frame
+        if health.current <= 0.0 {
+            continue;
+        }
+
+        let amount = armor.map_or(event.amount, |armor| armor.reduce(event.amount, event.kind));
+        health.current = (health.current - amount).max(0.0);
+        if health.current > 0.0 {
+            continue;
+        }
+
+        // Modules merged into a ship move with the ship's body
+        let (_, rotation, position) = global.to_scale_rotation_translation();
+        let module_velocity = match (velocity, parent.and_then(|p| body_query.get(p.get()).ok())) {
+            (Some(velocity), _) => *velocity,
+            (None, Some((ship_velocity, ship_global, mass_properties))) => {
+                let center_of_mass =
+                    ship_global.transform_point(mass_properties.local_center_of_mass);
+                Velocity {
+                    linvel: ship_velocity.linvel
+                        + ship_velocity.angvel.cross(position - center_of_mass),
+                    angvel: ship_velocity.angvel,
+                }
+            }
+            (None, None) => Velocity::zero(),
+        };
+        // Debris is blown away from whatever destroyed the module
+        let blast = event
+            .source
+            .and_then(|source| source_query.get(source).ok())
+            .map_or(Vec3::ZERO, |source| {
+                (position - source.translation()).normalize_or_zero() * DEBRIS_SPEED
+            });
+
+        spawn_debris(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            &mut rng.0,
+            Transform::from_translation(position).with_rotation(rotation),
+            size.0,
+            module_mass(size, mass),
+            color.0,
+            Velocity {
+                linvel: module_velocity.linvel + blast,
+                ..module_velocity
+            },
+        );
+        commands.entity(event.target).despawn_recursive();
+    }
+}
+
+/// Breaks a module into a grid of smaller boxes that fly apart from its center. Speeds and
+/// spins come from the simulation's RNG, so deterministic runs break modules the same way.
+fn spawn_debris(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+    rng: &mut fastrand::Rng,
+    transform: Transform,
+    size: Vec3,
+    mass: f32,
+    color: Color,
+    velocity: Velocity,
+) {
+    let piece_size = size / DEBRIS_DIVISIONS as f32;
+    let piece_count = DEBRIS_DIVISIONS.pow(3);
+
+    for x in 0..DEBRIS_DIVISIONS {
+        for y in 0..DEBRIS_DIVISIONS {
+            for z in 0..DEBRIS_DIVISIONS {
+                let offset =
+                    (Vec3::new(x as f32, y as f32, z as f32) + 0.5) * piece_size - size / 2.0;
+                let world_offset = transform.rotation * offset;
+                let piece_transform =
+                    Transform::from_translation(transform.translation + world_offset)
+                        .with_rotation(transform.rotation);
+
+                let speed = DEBRIS_SPEED * (0.5 + rng.f32());
+                let spin = Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - 1.0;
+
+                let mut piece = commands.spawn((
+                    Debris {
+                        lifetime: DEBRIS_LIFETIME,
+                    },
+                    RigidBody::Dynamic,
+                    Collider::cuboid(piece_size.x / 2.0, piece_size.y / 2.0, piece_size.z / 2.0),
+                    ColliderMassProperties::Mass(mass / piece_count as f32),
+                    ReadMassProperties::default(),
+                    ExternalForce::default(),
+                    Velocity {
+                        linvel: velocity.linvel
+                            + velocity.angvel.cross(world_offset)
+                            + world_offset.normalize_or_zero() * speed,
+                        angvel: velocity.angvel + spin * DEBRIS_SPIN,
+                    },
+                ));
+                if IS_HEADLESS {
+                    piece.insert(SpatialBundle {
+                        transform: piece_transform,
+                        ..Default::default()
+                    });
+                } else {
+                    piece.insert(PbrBundle {
+                        mesh: meshes.add(Mesh::from(shape::Box::new(
+                            piece_size.x,
+                            piece_size.y,
+                            piece_size.z,
+                        ))),
+                        material: materials.add(color.into()),
+                        transform: piece_transform,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+}
+
+pub fn despawn_debris(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut debris_query: Query<(Entity, &mut Debris)>,
+) {
+    for (entity, mut debris) in debris_query.iter_mut() {
+        debris.lifetime -= time.delta_seconds();
+        if debris.lifetime <= 0.0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
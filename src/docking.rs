@@ -0,0 +1,432 @@
+use bevy::{prelude::*, utils::HashSet};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    crosshair::CrosshairTarget,
+    input::Action,
+    module::{owning_body, ModuleTag, Size},
+    ship::{move_modules_to_ship, ModuleJoint, ShipTag},
+    ship_graph::ShipGraph,
+    ship_grid::{Face, GridPlacement, PortKind},
+    ship_mass::ShipMassProperties,
+    simulation::SimulationInput,
+};
+
+/// Ports further apart than this don't pull on each other.
+const DOCKING_RANGE: f32 = 3.0;
+/// Ports only pull on each other while their faces point at each other within about 30°.
+const DOCKING_ALIGNMENT: f32 = 0.85;
+/// Pull in newtons per meter between two ports.
+const DOCKING_STIFFNESS: f32 = 500.0;
+/// Force in newtons per meter per second that damps the relative motion of two ports.
+const DOCKING_DAMPING: f32 = 300.0;
+/// Torque in newton-meters that turns two ports to face each other.
+const DOCKING_TORQUE: f32 = 200.0;
+/// Ports closer than this and facing each other within about 5° are seated.
+const SEAT_DISTANCE: f32 = 0.05;
+const SEAT_ALIGNMENT: f32 = 0.996;
+/// Seconds after undocking during which a port doesn't pull on other ports.
+const DOCKING_COOLDOWN: f32 = 3.0;
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct DockingPort {
+    /// Face of the module the port is on
+    pub face: Face,
+    pub kind: PortKind,
+    /// The port this one is docked to
+    pub docked_to: Option<Entity>,
+    /// Seconds until the port can dock again
+    pub cooldown: f32,
+}
+
+impl DockingPort {
+    pub fn new(face: Face, kind: PortKind) -> Self {
+        DockingPort {
+            face,
+            kind,
+            docked_to: None,
+            cooldown: 0.0,
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.docked_to.is_none() && self.cooldown <= 0.0
+    }
+
+    /// Center of the port in the module's local space.
+    fn local_position(&self, size: Vec3) -> Vec3 {
+        self.face.normal() * size / 2.0
+    }
+}
+
+/// Sent when two ports of different ships are seated against each other.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DockEvent {
+    pub port: Entity,
+    pub other: Entity,
+}
+
+/// Rounds a rotation to the closest multiple of quarter turns around the axes.
+fn snap_rotation(rotation: Quat) -> Quat {
+    let snap = |v: Vec3| {
+        let abs = v.abs();
+        if abs.x >= abs.y && abs.x >= abs.z {
+            Vec3::X * v.x.signum()
+        } else if abs.y >= abs.z {
+            Vec3::Y * v.y.signum()
+        } else {
+            Vec3::Z * v.z.signum()
+        }
+    };
+    let x = snap(rotation * Vec3::X);
+    // Keep y perpendicular to the snapped x
+    let y_rotated = rotation * Vec3::Y;
+    let y = snap(y_rotated - x * y_rotated.dot(x));
+    Quat::from_mat3(&Mat3::from_cols(x, y, x.cross(y)))
+}
+
+struct FreePort {
+    port: Entity,
+    ship: Entity,
+    body: Entity,
+    kind: PortKind,
+    position: Vec3,
+    normal: Vec3,
+}
+
+type PortComponents<'a> = (
+    Entity,
+    &'a mut DockingPort,
+    &'a GlobalTransform,
+    &'a Size,
+    Option<&'a Parent>,
+    Has<RigidBody>,
+);
+
+/// Pulls free ports of different ships that are close and roughly lined up towards each
+/// other, and docks them once they are seated.
+pub fn align_docking_ports(
+    time: Res<Time>,
+    mut dock_events: EventWriter<DockEvent>,
+    ship_query: Query<(), With<ShipTag>>,
+    mut port_query: Query<PortComponents, With<ModuleTag>>,
+    mut body_query: Query<(
+        &GlobalTransform,
+        &ReadMassProperties,
+        Option<&Velocity>,
+        &mut ExternalForce,
+    )>,
+) {
+    let ship_of = |parent: Option<&Parent>| {
+        parent
+            .map(|parent| parent.get())
+            .filter(|parent| ship_query.contains(*parent))
+    };
+    let port_ships: Vec<(Entity, Option<Entity>)> = port_query
+        .iter()
+        .map(|(port, _, _, _, parent, _)| (port, ship_of(parent)))
+        .collect();
+    let ship_of_port = |port: Entity| {
+        port_ships
+            .iter()
+            .find(|(other, _)| *other == port)
+            .and_then(|(_, ship)| *ship)
+    };
+
+    let mut free_ports = Vec::new();
+    for (port, mut docking_port, global, size, parent, has_body) in port_query.iter_mut() {
+        docking_port.cooldown = (docking_port.cooldown - time.delta_seconds()).max(0.0);
+
+        // The connection broke, or the other port is gone
+        if let Some(other) = docking_port.docked_to {
+            if ship_of_port(other).is_none() || ship_of_port(other) != ship_of(parent) {
+                docking_port.docked_to = None;
+                docking_port.cooldown = DOCKING_COOLDOWN;
+            }
+        }
+        if !docking_port.is_free() {
+            continue;
+        }
+        let (Some(ship), Some(body)) = (ship_of(parent), owning_body(port, has_body, parent))
+        else {
+            continue;
+        };
+        let (_, rotation, _) = global.to_scale_rotation_translation();
+        free_ports.push(FreePort {
+            port,
+            ship,
+            body,
+            kind: docking_port.kind,
+            position: global.transform_point(docking_port.local_position(size.0)),
+            normal: rotation * docking_port.face.normal(),
+        });
+    }
+
+    let mut seated = HashSet::new();
+    for (index, a) in free_ports.iter().enumerate() {
+        for b in free_ports.iter().skip(index + 1) {
+            if a.ship == b.ship || !a.kind.is_compatible(b.kind) {
+                continue;
+            }
+            let offset = a.position - b.position;
+            let alignment = -a.normal.dot(b.normal);
+            if offset.length() > DOCKING_RANGE || alignment < DOCKING_ALIGNMENT {
+                continue;
+            }
+
+            if offset.length() < SEAT_DISTANCE && alignment > SEAT_ALIGNMENT {
+                if !seated.contains(&a.port) && !seated.contains(&b.port) {
+                    seated.insert(a.port);
+                    seated.insert(b.port);
+                    dock_events.send(DockEvent {
+                        port: a.port,
+                        other: b.port,
+                    });
+                }
+                continue;
+            }
+
+            // A damped spring between the ports, plus a torque that turns them face to face
+            let point_velocity = |body: Entity, point: Vec3| {
+                body_query
+                    .get(body)
+                    .ok()
+                    .and_then(|(global, mass_properties, velocity, _)| {
+                        let center_of_mass =
+                            global.transform_point(mass_properties.local_center_of_mass);
+                        velocity.map(|v| v.linvel + v.angvel.cross(point - center_of_mass))
+                    })
+                    .unwrap_or_default()
+            };
+            let relative_velocity =
+                point_velocity(b.body, b.position) - point_velocity(a.body, a.position);
+            let force = offset * DOCKING_STIFFNESS - relative_velocity * DOCKING_DAMPING;
+            let torque = b.normal.cross(-a.normal) * DOCKING_TORQUE;
+
+            for (body, point, sign) in [(b.body, b.position, 1.0), (a.body, a.position, -1.0)] {
+                if let Ok((global, mass_properties, _, mut external_force)) =
+                    body_query.get_mut(body)
+                {
+                    let center_of_mass =
+                        global.transform_point(mass_properties.local_center_of_mass);
+                    *external_force += ExternalForce::at_point(force * sign, point, center_of_mass);
+                    external_force.torque += torque * sign;
+                }
+            }
+        }
+    }
+}
+
+/// Welds the ships of two seated ports into one. The ship with fewer modules is moved
+/// onto the other one, lined up so that the ports sit exactly against each other.
+pub fn dock_ships(
+    mut commands: Commands,
+    mut dock_events: EventReader<DockEvent>,
+    ship_graph: Res<ShipGraph>,
+    mut port_query: Query<&mut DockingPort>,
+    mut ship_query: Query<
+        (
+            &GlobalTransform,
+            Option<&ShipMassProperties>,
+            Option<&mut Velocity>,
+        ),
+        With<ShipTag>,
+    >,
+    module_query: Query<(&GlobalTransform, &Size, Option<&ModuleJoint>), With<ModuleTag>>,
+) {
+    let mut docked_ships = HashSet::new();
+    for event in dock_events.read() {
+        let (Some(ship_a), Some(ship_b)) = (
+            ship_graph.ship_of(event.port),
+            ship_graph.ship_of(event.other),
+        ) else {
+            continue;
+        };
+        if ship_a == ship_b || docked_ships.contains(&ship_a) || docked_ships.contains(&ship_b) {
+            continue;
+        }
+
+        // The larger ship stays where it is
+        let modules_a = ship_graph.modules_of_ship(ship_a);
+        let modules_b = ship_graph.modules_of_ship(ship_b);
+        let (ship_a, ship_b, port_a, port_b, modules_b) = if modules_a.len() >= modules_b.len() {
+            (ship_a, ship_b, event.port, event.other, modules_b)
+        } else {
+            (ship_b, ship_a, event.other, event.port, modules_a)
+        };
+
+        let (Ok(docking_a), Ok(docking_b)) = (
+            port_query.get(port_a).copied(),
+            port_query.get(port_b).copied(),
+        ) else {
+            continue;
+        };
+        let (Ok((global_a, size_a, _)), Ok((global_b, size_b, _))) =
+            (module_query.get(port_a), module_query.get(port_b))
+        else {
+            continue;
+        };
+        let Ok((ship_global, ..)) = ship_query.get(ship_a) else {
+            continue;
+        };
+        let ship_global = *ship_global;
+
+        // Where port b's module has to be, relative to ship a, to sit exactly on port a
+        let local_a = global_a.reparented_to(&ship_global);
+        let normal_a = local_a.rotation * docking_a.face.normal();
+        let position_a = local_a.transform_point(docking_a.local_position(size_a.0));
+        let current_b = global_b.reparented_to(&ship_global);
+        let rotation_b = snap_rotation(current_b.rotation);
+        if (rotation_b * docking_b.face.normal()).dot(-normal_a) < SEAT_ALIGNMENT {
+            continue;
+        }
+        let seated_b = Transform::from_translation(
+            position_a - rotation_b * docking_b.local_position(size_b.0),
+        )
+        .with_rotation(rotation_b);
+        let correction = seated_b.compute_matrix() * current_b.compute_matrix().inverse();
+
+        let mut moved = Vec::new();
+        for module in modules_b.iter().copied() {
+            let Ok((global, size, _)) = module_query.get(module) else {
+                continue;
+            };
+            let transform = Transform::from_matrix(
+                correction * global.reparented_to(&ship_global).compute_matrix(),
+            );
+            let placement = GridPlacement::from_transform(&transform, size.0);
+            if placement.is_none() {
+                commands.entity(module).remove::<GridPlacement>();
+            }
+            moved.push((
+                module,
+                transform,
+                GlobalTransform::from(ship_global.compute_matrix() * transform.compute_matrix()),
+                placement,
+            ));
+        }
+        let new_transform = |module: Entity| {
+            moved
+                .iter()
+                .find(|(m, ..)| *m == module)
+                .map(|(_, transform, ..)| *transform)
+        };
+
+        // Port b becomes the root of its ship's joints, so that its own joint can attach
+        // it to port a. Every joint on the way up to the old root is turned around.
+        let mut child = port_b;
+        for _ in 0..modules_b.len() {
+            let Ok((_, _, Some(joint))) = module_query.get(child) else {
+                break;
+            };
+            let (Some(child_transform), Some(parent_transform)) =
+                (new_transform(child), new_transform(joint.parent))
+            else {
+                break;
+            };
+            let anchor = child_transform.transform_point(joint.anchor);
+            commands.entity(joint.parent).insert(ModuleJoint {
+                parent: child,
+                anchor: parent_transform
+                    .compute_affine()
+                    .inverse()
+                    .transform_point3(anchor),
+                ..*joint
+            });
+            child = joint.parent;
+        }
+        commands
+            .entity(port_b)
+            .insert(ModuleJoint::new(port_a, docking_b.local_position(size_b.0)));
+
+        // Modules welded to ship b's root would be welded to ship a's root once they move
+        // over, so they get a joint of their own and come off with ship b when undocking
+        let old_root = child;
+        for module in modules_b.iter().copied() {
+            if module != old_root
+                && module != port_b
+                && matches!(module_query.get(module), Ok((_, _, None)))
+            {
+                commands
+                    .entity(module)
+                    .insert(ModuleJoint::new(old_root, Vec3::ZERO));
+            }
+        }
+
+        // Momentum is kept when both ships move as one body
+        let velocity_b = ship_query
+            .get(ship_b)
+            .ok()
+            .and_then(|(_, mass, velocity)| Some((mass?.mass, *velocity?)));
+        if let Ok((_, Some(mass_a), Some(mut velocity_a))) = ship_query.get_mut(ship_a) {
+            if let Some((mass_b, velocity_b)) = velocity_b {
+                let total_mass = mass_a.mass + mass_b;
+                if total_mass > 0.0 {
+                    velocity_a.linvel =
+                        (velocity_a.linvel * mass_a.mass + velocity_b.linvel * mass_b) / total_mass;
+                }
+            }
+        }
+
+        move_modules_to_ship(
+            &mut commands,
+            ship_a,
+            &ship_global,
+            moved
+                .iter()
+                .map(|(module, _, global, placement)| (*module, global, placement.as_ref())),
+        );
+
+        for (port, other) in [(port_a, port_b), (port_b, port_a)] {
+            if let Ok(mut docking_port) = port_query.get_mut(port) {
+                docking_port.docked_to = Some(other);
+            }
+        }
+        docked_ships.insert(ship_a);
+        docked_ships.insert(ship_b);
+    }
+}
+
+/// Undocks the port under the crosshair, or every port of the ship under the crosshair.
+/// The ship then splits apart, and every piece keeps the velocity it had.
+pub fn undock_ships(
+    mut commands: Commands,
+    crosshair_target: Res<CrosshairTarget>,
+    input: Res<SimulationInput>,
+    mut port_query: Query<(Entity, &mut DockingPort, Option<&Parent>)>,
+    joint_query: Query<&ModuleJoint>,
+) {
+    if input.menu_open() {
+        return;
+    }
+    if !input.just_pressed(Action::Undock) {
+        return;
+    }
+
+    let targets: Vec<(Entity, Entity)> = port_query
+        .iter()
+        .filter(|(port, _, parent)| match crosshair_target.module {
+            Some(module) if port_query.contains(module) => *port == module,
+            _ => parent.map(|parent| parent.get()) == crosshair_target.ship,
+        })
+        .filter_map(|(port, docking_port, _)| docking_port.docked_to.map(|other| (port, other)))
+        .collect();
+
+    for (port, other) in targets {
+        // Only one of the two ports holds the joint between them
+        for (module, parent) in [(port, other), (other, port)] {
+            if joint_query
+                .get(module)
+                .is_ok_and(|joint| joint.parent == parent)
+            {
+                commands.entity(module).remove::<ModuleJoint>();
+            }
+        }
+        for port in [port, other] {
+            if let Ok((_, mut docking_port, _)) = port_query.get_mut(port) {
+                docking_port.docked_to = None;
+                docking_port.cooldown = DOCKING_COOLDOWN;
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    blueprint::{BlueprintModuleComponents, BlueprintModuleSource, ModuleOverrides},
+    input::Action,
+    module::{ModuleColor, ModuleTag},
+    module_definition::ModuleRegistry,
+    settings::ControlSettings,
+    ship::{move_modules_to_ship, spawn_ship, ModuleJoint, ShipTag},
+    ship_graph::ShipGraph,
+    ship_grid::GridPlacement,
+    simulation::SimulationInput,
+    IS_HEADLESS,
+};
+
+pub type SnapshotModuleComponents<'a> = (
+    &'a Parent,
+    &'a Transform,
+    Option<&'a GridPlacement>,
+    BlueprintModuleComponents<'a>,
+);
+
+/// Everything needed to spawn a removed module again.
+#[derive(Clone, Debug)]
+pub struct ModuleSnapshot {
+    pub module: Entity,
+    pub ship: Entity,
+    /// Global transform of the ship, used if the ship is gone by the time the module
+    /// is restored
+    pub ship_transform: Transform,
+    pub source: BlueprintModuleSource,
+    /// Color and fuel, which the source alone doesn't restore
+    pub overrides: ModuleOverrides,
+    /// Transform relative to the ship
+    pub transform: Transform,
+    pub placement: Option<GridPlacement>,
+    pub joint: Option<ModuleJoint>,
+    /// Joints of other modules that are attached to this one
+    pub dependents: Vec<(Entity, ModuleJoint)>,
+}
+
+impl ModuleSnapshot {
+    pub fn capture(
+        module: Entity,
+        ship_global: &GlobalTransform,
+        module_query: &Query<SnapshotModuleComponents, With<ModuleTag>>,
+        joint_query: &Query<(Entity, &ModuleJoint)>,
+    ) -> Option<Self> {
+        let (parent, transform, placement, components) = module_query.get(module).ok()?;
+        let (.., joint) = components;
+        Some(ModuleSnapshot {
+            module,
+            ship: parent.get(),
+            ship_transform: ship_global.compute_transform(),
+            source: BlueprintModuleSource::from_components(&components),
+            overrides: ModuleOverrides::from_components(&components),
+            transform: *transform,
+            placement: placement.copied(),
+            joint: joint.copied(),
+            dependents: joint_query
+                .iter()
+                .filter(|(_, joint)| joint.parent == module)
+                .map(|(child, joint)| (child, *joint))
+                .collect(),
+        })
+    }
+}
+
+/// A reversible ship editing operation, recorded after it was applied.
+#[derive(Clone, Debug)]
+pub enum EditCommand {
+    Place(ModuleSnapshot),
+    Remove(ModuleSnapshot),
+    Recolor {
+        module: Entity,
+        from: Color,
+        to: Color,
+    },
+    Rejoint {
+        module: Entity,
+        from: Option<ModuleJoint>,
+        to: Option<ModuleJoint>,
+    },
+}
+
+impl EditCommand {
+    fn inverse(&self) -> EditCommand {
+        match self {
+            EditCommand::Place(snapshot) => EditCommand::Remove(snapshot.clone()),
+            EditCommand::Remove(snapshot) => EditCommand::Place(snapshot.clone()),
+            EditCommand::Recolor { module, from, to } => EditCommand::Recolor {
+                module: *module,
+                from: *to,
+                to: *from,
+            },
+            EditCommand::Rejoint { module, from, to } => EditCommand::Rejoint {
+                module: *module,
+                from: *to,
+                to: *from,
+            },
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct EditHistory {
+    undo_stack: VecDeque<EditCommand>,
+    redo_stack: Vec<EditCommand>,
+    /// Modules and ships that were respawned by undo or redo, mapped to their new entity
+    respawned: HashMap<Entity, Entity>,
+}
+
+impl EditHistory {
+    /// Records a command that was just applied. Only the last `depth` commands are kept.
+    pub fn push(&mut self, command: EditCommand, depth: usize) {
+        self.redo_stack.clear();
+        self.push_undo(command, depth);
+    }
+
+    /// Adds a command to the undo stack without touching the redo stack.
+    fn push_undo(&mut self, command: EditCommand, depth: usize) {
+        self.undo_stack.push_back(command);
+        while self.undo_stack.len() > depth {
+            self.undo_stack.pop_front();
+        }
+    }
+
+    /// The entity that currently stands for `entity`, following respawns.
+    fn resolve(&self, mut entity: Entity) -> Entity {
+        while let Some(respawned) = self.respawned.get(&entity) {
+            entity = *respawned;
+        }
+        entity
+    }
+
+    fn resolve_joint(&self, joint: &ModuleJoint) -> ModuleJoint {
+        ModuleJoint {
+            parent: self.resolve(joint.parent),
+            ..*joint
+        }
+    }
+}
+
+pub fn set_module_color(
+    commands: &mut Commands,
+    module: Entity,
+    color: Color,
+    material: Option<&Handle<StandardMaterial>>,
+    materials: &mut Assets<StandardMaterial>,
+) {
+    commands.entity(module).insert(ModuleColor(color));
+    if let Some(material) = material.and_then(|material| materials.get_mut(material)) {
+        material.base_color = color;
+    }
+}
+
+pub fn undo_redo_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut history: ResMut<EditHistory>,
+    registry: Res<ModuleRegistry>,
+    control_settings: Res<ControlSettings>,
+    ship_graph: Res<ShipGraph>,
+    input: Res<SimulationInput>,
+    ship_query: Query<&GlobalTransform, With<ShipTag>>,
+    module_query: Query<
+        (
+            &GlobalTransform,
+            Option<&GridPlacement>,
+            Option<&Handle<StandardMaterial>>,
+        ),
+        With<ModuleTag>,
+    >,
+) {
+    if input.menu_open() {
+        return;
+    }
+
+    // Undo applies the inverse of the last command, redo applies the command again
+    let to_apply = if input.just_pressed(Action::Undo) {
+        let Some(command) = history.undo_stack.pop_back() else {
+            return;
+        };
+        let inverse = command.inverse();
+        history.redo_stack.push(command);
+        inverse
+    } else if input.just_pressed(Action::Redo) {
+        let Some(command) = history.redo_stack.pop() else {
+            return;
+        };
+        history.push_undo(command.clone(), control_settings.undo_history_depth);
+        command
+    } else {
+        return;
+    };
+
+    match to_apply {
+        EditCommand::Place(snapshot) => {
+            let mut ship = history.resolve(snapshot.ship);
+            if !ship_query.contains(ship) {
+                ship = spawn_ship(&mut commands, snapshot.ship_transform, Velocity::default());
+                history.respawned.insert(snapshot.ship, ship);
+            }
+            let Some(module) = snapshot.source.spawn(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &registry,
+                ship,
+                snapshot.transform,
+                &snapshot.overrides,
+                IS_HEADLESS,
+            ) else {
+                return;
+            };
+            history.respawned.insert(snapshot.module, module);
+
+            if let Some(placement) = snapshot.placement {
+                commands.entity(module).insert(placement);
+            }
+            if let Some(joint) = &snapshot.joint {
+                commands.entity(module).insert(history.resolve_joint(joint));
+            }
+
+            // Modules that were attached to this one may have split off into other ships
+            let ship_global = ship_query
+                .get(ship)
+                .copied()
+                .unwrap_or(GlobalTransform::from(snapshot.ship_transform));
+            for (dependent, joint) in snapshot.dependents.iter() {
+                let dependent = history.resolve(*dependent);
+                if !module_query.contains(dependent) {
+                    continue;
+                }
+                commands.entity(dependent).insert(ModuleJoint {
+                    parent: module,
+                    ..*joint
+                });
+
+                let Some(dependent_ship) = ship_graph.ship_of(dependent) else {
+                    continue;
+                };
+                if dependent_ship != ship {
+                    let piece = ship_graph.modules_of_ship(dependent_ship);
+                    move_modules_to_ship(
+                        &mut commands,
+                        ship,
+                        &ship_global,
+                        piece.into_iter().filter_map(|m| {
+                            module_query
+                                .get(m)
+                                .ok()
+                                .map(|(global, placement, _)| (m, global, placement))
+                        }),
+                    );
+                }
+            }
+        }
+        EditCommand::Remove(snapshot) => {
+            let module = history.resolve(snapshot.module);
+            if module_query.contains(module) {
+                commands.entity(module).despawn_recursive();
+            }
+        }
+        EditCommand::Recolor { module, to, .. } => {
+            let module = history.resolve(module);
+            if let Ok((_, _, material)) = module_query.get(module) {
+                set_module_color(&mut commands, module, to, material, &mut materials);
+            }
+        }
+        EditCommand::Rejoint { module, to, .. } => {
+            let module = history.resolve(module);
+            if !module_query.contains(module) {
+                return;
+            }
+            match to {
+                Some(joint) => commands
+                    .entity(module)
+                    .insert(history.resolve_joint(&joint)),
+                None => commands.entity(module).remove::<ModuleJoint>(),
+            };
+        }
+    }
+}
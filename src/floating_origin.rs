@@ -0,0 +1,93 @@
+use bevy::{math::DVec3, prelude::*};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    build_mode::BuildGhost, gravity::GravitySource, ship::ShipTag, simulation::SimulationInput,
+};
+
+/// Edge length of a sector in meters.
+pub const SECTOR_SIZE: f32 = 1000.0;
+/// The world is shifted once the camera gets further than this from the origin along any
+/// axis. Being larger than half a sector keeps the world from shifting back and forth while
+/// the camera moves along a sector border.
+const RECENTER_DISTANCE: f32 = SECTOR_SIZE;
+
+/// The sector at the center of the world. Positions of entities are relative to it.
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FloatingOrigin {
+    pub sector: IVec3,
+}
+
+impl FloatingOrigin {
+    /// Position in the whole play area, with enough precision for very large maps.
+    pub fn absolute_position(&self, translation: Vec3) -> DVec3 {
+        self.sector.as_dvec3() * SECTOR_SIZE as f64 + translation.as_dvec3()
+    }
+}
+
+/// Sector an entity is in. Kept up to date for everything the floating origin moves.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sector(pub IVec3);
+
+/// Entities placed directly in world space, which the floating origin moves. Modules and
+/// other children follow their parent.
+pub type WorldSpaceFilter = (
+    Without<Parent>,
+    Or<(
+        With<RigidBody>,
+        With<ShipTag>,
+        With<GravitySource>,
+        With<Camera3d>,
+        With<PointLight>,
+        With<BuildGhost>,
+    )>,
+);
+
+/// Sent after the world was shifted by `offset`, for anything that stores world positions.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct OriginShiftEvent {
+    pub offset: Vec3,
+}
+
+/// Moves the world back towards the origin when the simulation's camera gets too far
+/// away. Every entity in world space is moved by the same whole number of sectors in the
+/// same frame, so nothing moves relative to the camera. Rapier bodies are moved along with
+/// their transforms before the physics step, while velocities and joint anchors are
+/// relative and stay as they are.
+pub fn recenter_floating_origin(
+    mut origin: ResMut<FloatingOrigin>,
+    mut shift_events: EventWriter<OriginShiftEvent>,
+    mut input: ResMut<SimulationInput>,
+    mut root_query: Query<&mut Transform, WorldSpaceFilter>,
+) {
+    let camera = *input.camera();
+    if camera.translation.abs().max_element() <= RECENTER_DISTANCE {
+        return;
+    }
+
+    let shift = (camera.translation / SECTOR_SIZE).round().as_ivec3();
+    let offset = -shift.as_vec3() * SECTOR_SIZE;
+    for mut transform in root_query.iter_mut() {
+        transform.translation += offset;
+    }
+    input.shift_camera(offset);
+    origin.sector += shift;
+    shift_events.send(OriginShiftEvent { offset });
+}
+
+pub fn update_sectors(
+    mut commands: Commands,
+    origin: Res<FloatingOrigin>,
+    mut root_query: Query<(Entity, &Transform, Option<&mut Sector>), WorldSpaceFilter>,
+) {
+    for (entity, transform, sector) in root_query.iter_mut() {
+        let current = origin.sector + (transform.translation / SECTOR_SIZE).round().as_ivec3();
+        match sector {
+            Some(mut sector) if sector.0 != current => sector.0 = current,
+            Some(_) => {}
+            None => {
+                commands.entity(entity).insert(Sector(current));
+            }
+        }
+    }
+}
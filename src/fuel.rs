@@ -0,0 +1,96 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    module::{engine_thrust, ModuleEngineTag, ModuleMass},
+    power::PowerConsumer,
+    ship_controls::Throttle,
+    ship_graph::ShipGraph,
+    thruster_allocation::EngineThrottle,
+};
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct FuelTank {
+    /// Propellant in kilograms when full
+    pub capacity: f32,
+    /// Propellant in kilograms that is left
+    pub fuel: f32,
+    /// Mass of the empty tank
+    pub dry_mass: f32,
+}
+
+/// Marks engines that can't reach enough propellant for a step and produce no thrust.
+#[derive(Component)]
+pub struct Flameout;
+
+/// Draws propellant for every engine from the tanks connected to it through joints, and
+/// flames out engines that can't draw all the propellant they need.
+pub fn consume_fuel(
+    mut commands: Commands,
+    time: Res<Time>,
+    ship_graph: Res<ShipGraph>,
+    engine_query: Query<(
+        Entity,
+        &ModuleEngineTag,
+        Option<&EngineThrottle>,
+        Option<&PowerConsumer>,
+        Option<&Parent>,
+        Has<Flameout>,
+    )>,
+    throttle_query: Query<&Throttle>,
+    mut tank_query: Query<(
+        &mut FuelTank,
+        &mut ModuleMass,
+        Option<&mut ColliderMassProperties>,
+    )>,
+) {
+    for (engine, engine_tag, engine_throttle, power, parent, flameout) in engine_query.iter() {
+        // Sorted, so that tanks are drained in a stable order
+        let tanks: Vec<Entity> = ship_graph
+            .connected_modules(engine)
+            .into_iter()
+            .filter(|module| tank_query.contains(*module))
+            .collect();
+
+        let available: f32 = tanks
+            .iter()
+            .filter_map(|tank| tank_query.get(*tank).ok())
+            .map(|(tank, ..)| tank.fuel)
+            .sum();
+        let ship_throttle = parent.and_then(|parent| throttle_query.get(parent.get()).ok());
+        let thrust = engine_thrust(engine_tag, engine_throttle, ship_throttle, power);
+        let mut needed = thrust / engine_tag.exhaust_velocity * time.delta_seconds();
+
+        // Engines don't fire on a partial draw, the thrust of this step would be free
+        if available <= 0.0 || available < needed {
+            if !flameout {
+                commands.entity(engine).insert(Flameout);
+            }
+            continue;
+        }
+        if flameout {
+            commands.entity(engine).remove::<Flameout>();
+        }
+
+        for tank in tanks {
+            if needed <= 0.0 {
+                break;
+            }
+            let Ok((mut fuel_tank, mut mass, collider_mass)) = tank_query.get_mut(tank) else {
+                continue;
+            };
+            let drawn = needed.min(fuel_tank.fuel);
+            if drawn <= 0.0 {
+                continue;
+            }
+            needed -= drawn;
+            fuel_tank.fuel -= drawn;
+
+            // Lighter tanks make the ship accelerate faster
+            mass.0 = fuel_tank.dry_mass + fuel_tank.fuel;
+            if let Some(mut collider_mass) = collider_mass {
+                *collider_mass = ColliderMassProperties::Mass(mass.0);
+            }
+        }
+    }
+}
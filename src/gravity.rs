@@ -0,0 +1,75 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+/// Newtonian constant of gravitation, in m³/(kg·s²).
+pub const GRAVITATIONAL_CONSTANT: f32 = 6.674e-11;
+/// Distances below this are treated as this, so that bodies passing through the center of a
+/// source don't get flung away.
+const MIN_GRAVITY_DISTANCE: f32 = 1.0;
+
+/// A point mass that pulls on every dynamic body. Sources with a dynamic body of their own
+/// are pulled by the other sources too, so they can orbit each other.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct GravitySource {
+    /// Mass in kilograms
+    pub mass: f32,
+    /// Bodies further away than this in meters are not pulled
+    pub influence_radius: Option<f32>,
+    /// Radius of the surface in meters, used to predict impacts. Predicted paths pass
+    /// through sources without one.
+    pub radius: Option<f32>,
+}
+
+/// Acceleration at `position` caused by the sources given with their position.
+pub fn gravity_acceleration<'a>(
+    position: Vec3,
+    sources: impl IntoIterator<Item = (Vec3, &'a GravitySource)>,
+) -> Vec3 {
+    let mut acceleration = Vec3::ZERO;
+    for (source_position, source) in sources {
+        let offset = source_position - position;
+        let distance = offset.length();
+        if source
+            .influence_radius
+            .is_some_and(|radius| distance > radius)
+        {
+            continue;
+        }
+        let distance = distance.max(MIN_GRAVITY_DISTANCE);
+        acceleration += offset.normalize_or_zero() * GRAVITATIONAL_CONSTANT * source.mass
+            / (distance * distance);
+    }
+    acceleration
+}
+
+/// Applies inverse-square gravity from every source to every dynamic body, at the body's
+/// center of mass. A source doesn't pull on itself.
+pub fn apply_gravity(
+    source_query: Query<(Entity, &GravitySource, &GlobalTransform)>,
+    mut body_query: Query<(
+        Entity,
+        &RigidBody,
+        &GlobalTransform,
+        &ReadMassProperties,
+        &mut ExternalForce,
+    )>,
+) {
+    if source_query.is_empty() {
+        return;
+    }
+
+    for (body, rigid_body, global, mass_properties, mut external_force) in body_query.iter_mut() {
+        if *rigid_body != RigidBody::Dynamic {
+            continue;
+        }
+        let center_of_mass = global.transform_point(mass_properties.local_center_of_mass);
+        let acceleration = gravity_acceleration(
+            center_of_mass,
+            source_query
+                .iter()
+                .filter(|(source, ..)| *source != body)
+                .map(|(_, source, source_global)| (source_global.translation(), source)),
+        );
+        external_force.force += acceleration * mass_properties.mass;
+    }
+}
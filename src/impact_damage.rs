@@ -0,0 +1,121 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    damage::{DamageEvent, DamageType},
+    module::ModuleTag,
+    ship::{ShipColliderModules, ShipTag},
+};
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ImpactDamageSettings {
+    /// Contact forces in newtons below this never cause damage, which keeps resting
+    /// contacts and gentle docking harmless
+    pub min_force: f32,
+    /// Damage per unit of impulse above the minimum, after the exponent is applied
+    pub damage_scale: f32,
+    /// Values above 1 make hard impacts disproportionately more damaging than soft ones
+    pub exponent: f32,
+}
+
+impl Default for ImpactDamageSettings {
+    fn default() -> Self {
+        ImpactDamageSettings {
+            min_force: 50_000.0,
+            damage_scale: 1e-4,
+            exponent: 1.5,
+        }
+    }
+}
+
+/// Damage caused by an impulse in newton seconds that was delivered over a physics
+/// step of `dt` seconds.
+pub fn impact_damage(impulse: f32, dt: f32, settings: &ImpactDamageSettings) -> f32 {
+    let excess = impulse - settings.min_force * dt;
+    if excess <= 0.0 {
+        return 0.0;
+    }
+    settings.damage_scale * excess.powf(settings.exponent)
+}
+
+/// Makes the colliders of modules and merged ships report contact forces above the
+/// minimum, and keeps the threshold in sync with the settings.
+pub fn enable_contact_force_events(
+    mut commands: Commands,
+    settings: Res<ImpactDamageSettings>,
+    collider_query: Query<(Entity, Ref<Collider>), Or<(With<ModuleTag>, With<ShipTag>)>>,
+) {
+    for (entity, collider) in collider_query.iter() {
+        if settings.is_changed() || collider.is_added() {
+            commands.entity(entity).insert((
+                ActiveEvents::CONTACT_FORCE_EVENTS,
+                ContactForceEventThreshold(settings.min_force),
+            ));
+        }
+    }
+}
+
+/// The module behind `collider`. Merged ships have one compound collider, and record which
+/// module each of its shapes belongs to.
+fn collider_module(
+    collider: Entity,
+    subshape: u32,
+    ship_query: &Query<&ShipColliderModules>,
+    module_query: &Query<(), With<ModuleTag>>,
+) -> Option<Entity> {
+    if module_query.contains(collider) {
+        return Some(collider);
+    }
+    ship_query
+        .get(collider)
+        .ok()?
+        .0
+        .get(subshape as usize)
+        .copied()
+        .filter(|module| module_query.contains(*module))
+}
+
+/// Turns the impulse of hard collisions into damage on the modules on both sides.
+pub fn apply_impact_damage(
+    rapier_context: Res<RapierContext>,
+    settings: Res<ImpactDamageSettings>,
+    mut contact_force_events: EventReader<ContactForceEvent>,
+    mut damage_events: EventWriter<DamageEvent>,
+    ship_query: Query<&ShipColliderModules>,
+    module_query: Query<(), With<ModuleTag>>,
+) {
+    let dt = rapier_context.integration_parameters.dt;
+
+    for event in contact_force_events.read() {
+        let Some(pair) = rapier_context.contact_pair(event.collider1, event.collider2) else {
+            continue;
+        };
+        let (collider1, collider2) = (pair.collider1(), pair.collider2());
+
+        for manifold in pair.manifolds() {
+            let impulse: f32 = manifold.points().map(|point| point.impulse()).sum();
+            let damage = impact_damage(impulse, dt, &settings);
+            if damage <= 0.0 {
+                continue;
+            }
+
+            let module1 =
+                collider_module(collider1, manifold.subshape1(), &ship_query, &module_query);
+            let module2 =
+                collider_module(collider2, manifold.subshape2(), &ship_query, &module_query);
+            for (target, source) in [
+                (module1, module2.unwrap_or(collider2)),
+                (module2, module1.unwrap_or(collider1)),
+            ] {
+                if let Some(target) = target {
+                    damage_events.send(DamageEvent {
+                        target,
+                        amount: damage,
+                        kind: DamageType::Kinetic,
+                        source: Some(source),
+                    });
+                }
+            }
+        }
+    }
+}
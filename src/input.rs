@@ -1,9 +1,23 @@
 use bevy::reflect::Reflect;
 use leafwing_input_manager::prelude::*;
+use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumIter};
 
 //TODO: Move this to an input-handling file
-#[derive(Actionlike, Reflect, PartialEq, Eq, Clone, Copy, Hash, Debug, EnumIter, Display)]
+#[derive(
+    Actionlike,
+    Reflect,
+    PartialEq,
+    Eq,
+    Clone,
+    Copy,
+    Hash,
+    Debug,
+    EnumIter,
+    Display,
+    Serialize,
+    Deserialize,
+)]
 pub enum Action {
     Exit,
     Forward,
@@ -13,4 +27,25 @@ pub enum Action {
     Jump,
     Crouch,
     Sprint,
+    ToggleBuildMode,
+    PlaceModule,
+    RemoveModule,
+    RotateModule,
+    CycleModule,
+    PaintModule,
+    RejointModule,
+    Undo,
+    Redo,
+    SaveBlueprint,
+    LoadBlueprint,
+    ThrottleUp,
+    ThrottleDown,
+    ThrottleCut,
+    FullThrottle,
+    ToggleEngines,
+    Undock,
+    RotorForward,
+    RotorBackward,
+    WarpFaster,
+    WarpSlower,
 }
@@ -0,0 +1,53 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::ship::ModuleJoint;
+
+/// Load on a module's connection during the last physics step.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct JointStress {
+    pub force: f32,
+    pub torque: f32,
+}
+
+/// Reads the impulses Rapier applied at each module joint and breaks the connections that
+/// went over their limits. Only jointed ships have joints in Rapier, merged ships are rigid.
+pub fn update_joint_stress(
+    mut commands: Commands,
+    rapier_context: Res<RapierContext>,
+    mut joint_query: Query<(
+        Entity,
+        &ModuleJoint,
+        &RapierImpulseJointHandle,
+        Option<&mut JointStress>,
+    )>,
+) {
+    let dt = rapier_context.integration_parameters.dt;
+    if dt <= 0.0 {
+        return;
+    }
+
+    for (module, joint, handle, stress) in joint_query.iter_mut() {
+        let Some(impulse_joint) = rapier_context.impulse_joints.get(handle.0) else {
+            continue;
+        };
+        let impulses = impulse_joint.impulses;
+        let new_stress = JointStress {
+            force: Vec3::new(impulses[0], impulses[1], impulses[2]).length() / dt,
+            torque: Vec3::new(impulses[3], impulses[4], impulses[5]).length() / dt,
+        };
+
+        match stress {
+            Some(mut stress) => *stress = new_stress,
+            None => {
+                commands.entity(module).insert(new_stress);
+            }
+        }
+
+        if new_stress.force > joint.break_force || new_stress.torque > joint.break_torque {
+            commands
+                .entity(module)
+                .remove::<(ModuleJoint, JointStress)>();
+        }
+    }
+}
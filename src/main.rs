@@ -1,10 +1,36 @@
+mod blueprint;
+mod build_mode;
+mod crosshair;
+mod damage;
+mod docking;
+mod edit_history;
+mod floating_origin;
+mod fuel;
+mod gravity;
+mod impact_damage;
 mod input;
+mod joint_stress;
 mod menu_focus;
 mod module;
+mod module_definition;
+mod power;
 mod render_utils;
+mod rotor;
 mod settings;
 mod settings_io;
+mod ship;
+mod ship_controls;
+mod ship_graph;
+mod ship_grid;
+mod ship_mass;
+mod ship_split;
+mod simulation;
 mod spectator_camera;
+#[cfg(test)]
+mod test_utils;
+mod thruster_allocation;
+mod time_warp;
+mod trajectory;
 mod ui_menu;
 
 use bevy::prelude::*;
@@ -12,21 +38,49 @@ use bevy_egui::EguiPlugin;
 
 use bevy_pkv::PkvStore;
 use bevy_rapier3d::prelude::*;
-use leafwing_input_manager::{prelude::InputManagerPlugin, InputManagerBundle};
+use blueprint::blueprint_system;
+use build_mode::{build_mode_system, update_build_mode, BuildMode};
+use crosshair::{update_crosshair_target, CrosshairTarget};
+use damage::{apply_damage, despawn_debris, DamageEvent};
+use docking::{align_docking_ports, dock_ships, undock_ships, DockEvent};
+use edit_history::{undo_redo_system, EditHistory};
+use floating_origin::{recenter_floating_origin, update_sectors, FloatingOrigin, OriginShiftEvent};
+use fuel::consume_fuel;
+use gravity::apply_gravity;
+use impact_damage::{apply_impact_damage, enable_contact_force_events, ImpactDamageSettings};
+use joint_stress::update_joint_stress;
+use leafwing_input_manager::{
+    plugin::InputManagerSystem, prelude::InputManagerPlugin, InputManagerBundle,
+};
 use menu_focus::CursorLockState;
-use module::{engine_system, spawn_base_module, ModuleEngineTag};
+use module::engine_system;
+use module_definition::{ModuleRegistry, MODULE_DEFINITION_DIR};
+use power::update_power_networks;
 use render_utils::update_window;
+use rotor::{control_rotors, update_rotor_motors};
 use settings::*;
 use settings_io::*;
+use ship::*;
+use ship_controls::update_throttle;
+use ship_graph::{sync_ship_graph, ShipGraph};
+use ship_mass::update_ship_mass_properties;
+use ship_split::{remove_stale_joints, split_disconnected_ships, ShipSplitEvent};
+use simulation::{
+    add_simulation, advance_simulation_input, latch_simulation_input, save_input_recording,
+    InputRecording, SimulationInput, SimulationMode, SimulationSet, INPUT_RECORDING_PATH,
+};
 use spectator_camera::*;
+use thruster_allocation::{allocate_ship_thrust, update_thrust_requests};
+use time_warp::{apply_time_warp, control_time_warp, show_time_warp, TimeWarp};
+use trajectory::{
+    draw_trajectories, predict_trajectories, refresh_split_trajectories, shift_trajectories,
+    TrajectorySettings,
+};
 use ui_menu::*;
 
 const IS_HEADLESS: bool = true;
-
-/// TODO: Since it is not efficient to have a joint between each module,
-/// we could create far away entities to have composite colliders instead,
-/// and dynamically switch it out when the player (or other active entity
-/// like a bullet) gets close.
+/// Force the camera ray pushes bodies with, in newtons.
+const CAST_RAY_FORCE: f32 = 100.0;
 
 fn setup(
     mut commands: Commands,
@@ -36,49 +90,55 @@ fn setup(
     mut rapier_config: ResMut<RapierConfiguration>,
     graphics_settings: Res<GraphicsSettings>,
     cursor_lock_state: Res<CursorLockState>,
+    module_registry: Res<ModuleRegistry>,
 ) {
-    // Test Module
-    let module1 = spawn_base_module(
+    // Test Ship
+    let ship = spawn_ship(
+        &mut commands,
+        Transform::from_xyz(0.0, 1.0, 0.0),
+        Velocity::default(),
+    );
+
+    let module1 = module_registry.spawn_in_ship(
+        "engine",
         &mut commands,
         &mut meshes,
         &mut materials,
-        Vec3::new(1.0, 1.0, 1.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        Quat::IDENTITY,
-        Color::rgb(0.8, 0.2, 0.2),
-        Velocity::default(),
+        ship,
+        Transform::IDENTITY,
         IS_HEADLESS,
     );
 
-    let module2 = spawn_base_module(
+    let module2 = module_registry.spawn_in_ship(
+        "hull",
         &mut commands,
         &mut meshes,
         &mut materials,
-        Vec3::new(1.0, 1.0, 1.0),
-        Vec3::new(1.0, 1.0, 0.0),
-        Quat::from_euler(EulerRot::XYZ, 0.0, 0.0, 0.0),
-        Color::rgb(0.2, 0.8, 0.2),
-        Velocity::default(),
+        ship,
+        Transform::from_xyz(1.0, 0.0, 0.0),
         IS_HEADLESS,
     );
 
-    commands
-        .entity(module1)
-        .insert(ModuleEngineTag { thrust: 1.0 });
-
-    commands
-        .entity(module2)
-        .insert(ModuleEngineTag { thrust: 1.0 });
-
-    let mut joint_data = FixedJointBuilder::new()
-        .local_anchor1(Vec3::new(-0.5, 0.0, 0.0))
-        .local_anchor2(Vec3::new(0.5, 0.0, 0.0))
-        .build();
-    joint_data.set_contacts_enabled(false);
+    let module3 = module_registry.spawn_in_ship(
+        "fuel_tank",
+        &mut commands,
+        &mut meshes,
+        &mut materials,
+        ship,
+        Transform::from_xyz(-1.0, 0.0, 0.0),
+        IS_HEADLESS,
+    );
 
-    commands
-        .entity(module2)
-        .insert(ImpulseJoint::new(module1, joint_data));
+    if let (Some(module1), Some(module2)) = (module1, module2) {
+        commands
+            .entity(module2)
+            .insert(ModuleJoint::new(module1, Vec3::new(-0.5, 0.0, 0.0)));
+    }
+    if let (Some(module1), Some(module3)) = (module1, module3) {
+        commands
+            .entity(module3)
+            .insert(ModuleJoint::new(module1, Vec3::new(0.5, 0.0, 0.0)));
+    }
 
     // Light
     commands.spawn(PointLightBundle {
@@ -91,70 +151,280 @@ fn setup(
         ..default()
     });
     // Spectator Camera
-    commands.spawn(SpectatorCameraBundle {
-        input_manager: InputManagerBundle {
-            input_map: SpectatorCameraBundle::default_input_map(),
-            ..default()
-        },
-        camera: Camera3dBundle {
-            projection: Projection::Perspective(PerspectiveProjection {
-                fov: f32::to_radians(graphics_settings.fov.into()),
-                ..Default::default()
-            }),
-            transform: Transform::from_xyz(0.0, 2.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-            ..default()
+    commands.spawn((
+        SpectatorCameraBundle {
+            input_manager: InputManagerBundle {
+                input_map: SpectatorCameraBundle::default_input_map(),
+                ..default()
+            },
+            camera: Camera3dBundle {
+                projection: Projection::Perspective(PerspectiveProjection {
+                    fov: f32::to_radians(graphics_settings.fov.into()),
+                    ..Default::default()
+                }),
+                transform: Transform::from_xyz(0.0, 2.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+                ..default()
+            },
         },
-    });
+        ProximityTrigger,
+    ));
 
     set_cursor_lock(&mut windows.single_mut(), cursor_lock_state);
     rapier_config.gravity = Vec3::ZERO;
 }
 
+/// Clears the forces of last frame, systems that apply forces add to them afterwards.
+fn reset_external_forces(mut query: Query<&mut ExternalForce>) {
+    for mut external_force in query.iter_mut() {
+        *external_force = ExternalForce::default();
+    }
+}
+
 fn cast_ray_system(
-    mut commands: Commands,
     rapier_context: Res<RapierContext>,
-    camera_query: Query<&GlobalTransform, With<Camera3d>>,
-    time: Res<Time>,
+    input: Res<SimulationInput>,
+    mut force_query: Query<&mut ExternalForce>,
+    build_mode: Res<BuildMode>,
 ) {
-    for transform in camera_query.iter() {
-        if let Some((entity, _)) = rapier_context.cast_ray(
-            transform.translation(),
-            transform.forward(),
-            100.0,
-            true,
-            QueryFilter::default(),
-        ) {
-            // Apply force to hit entity
-            let force_magnitude = 100.0 * time.delta().as_secs_f32();
-            let force_direction = (transform.back()).normalize();
-            let force_vector = force_direction * force_magnitude;
-
-            commands.entity(entity).insert(ExternalForce {
-                force: force_vector,
-                torque: Vec3::ZERO,
-            });
+    if build_mode.active {
+        return;
+    }
+    let transform = input.camera();
+    if let Some((entity, _)) = rapier_context.cast_ray(
+        transform.translation,
+        transform.forward(),
+        100.0,
+        true,
+        QueryFilter::default(),
+    ) {
+        // Apply force to hit entity, Rapier already scales it by the step
+        let force_direction = (transform.back()).normalize();
+        let force_vector = force_direction * CAST_RAY_FORCE;
+
+        if let Ok(mut external_force) = force_query.get_mut(entity) {
+            external_force.force += force_vector;
         }
     }
 }
 
 fn main() {
-    App::new()
-        .insert_resource(PkvStore::new("aetherion", "game"))
+    let mut simulation_mode = SimulationMode::from_args(std::env::args());
+    // Systems that change the simulation state, fixed in deterministic mode
+    let simulation = simulation_mode.gameplay_schedule();
+
+    let mut simulation_input = SimulationInput::default();
+    if simulation_mode.deterministic {
+        simulation_input.start_recording();
+    }
+    if simulation_mode.replay {
+        match InputRecording::load(INPUT_RECORDING_PATH) {
+            Ok(recording) => {
+                simulation_mode.seed = recording.seed;
+                simulation_input.replay(recording.steps);
+            }
+            Err(e) => println!(
+                "Failed to load input recording {}: {}",
+                INPUT_RECORDING_PATH, e
+            ),
+        }
+    }
+
+    let mut app = App::new();
+    app.insert_resource(PkvStore::new("aetherion", "game"))
         .insert_resource(CursorLockState(true))
         .insert_resource(ControlSettings::default())
         .insert_resource(GraphicsSettings::default())
         .insert_resource(UiVisibility::default())
-        .add_plugins(DefaultPlugins)
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
-        .add_plugins(RapierDebugRenderPlugin::default())
+        .insert_resource(ShipLayoutSettings::default())
+        .insert_resource(ModuleRegistry::load_from_dir(MODULE_DEFINITION_DIR))
+        .insert_resource(ShipGraph::default())
+        .insert_resource(BuildMode::default())
+        .insert_resource(EditHistory::default())
+        .insert_resource(CrosshairTarget::default())
+        .insert_resource(ImpactDamageSettings::default())
+        .insert_resource(FloatingOrigin::default())
+        .insert_resource(TrajectorySettings::default())
+        .insert_resource(TimeWarp::default())
+        .insert_resource(simulation_input)
+        .add_plugins(DefaultPlugins);
+    add_simulation(&mut app, simulation_mode);
+    app.add_plugins(RapierDebugRenderPlugin::default())
         .add_plugins(EguiPlugin)
         .add_plugins(InputManagerPlugin::<input::Action>::default())
+        .add_event::<ShipSplitEvent>()
+        .add_event::<DamageEvent>()
+        .add_event::<DockEvent>()
+        .add_event::<OriginShiftEvent>()
         .add_systems(Startup, import_player_settings)
         .add_systems(Startup, setup)
         .add_systems(Startup, update_window)
         .add_systems(Update, move_camera)
         .add_systems(Update, ui_menu)
-        // .add_systems(Update, engine_system)
-        .add_systems(Update, cast_ray_system)
-        .run();
+        .add_systems(Update, control_time_warp)
+        .add_systems(Update, apply_time_warp.after(control_time_warp))
+        .add_systems(Update, show_time_warp)
+        .add_systems(
+            PreUpdate,
+            latch_simulation_input.after(InputManagerSystem::Update),
+        )
+        .add_systems(Last, save_input_recording)
+        .add_systems(simulation, reset_external_forces.in_set(SimulationSet))
+        .add_systems(simulation, update_crosshair_target.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            update_throttle
+                .after(update_crosshair_target)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            update_ship_mass_properties.in_set(SimulationSet),
+        )
+        .add_systems(simulation, update_power_networks.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            update_thrust_requests
+                .after(update_ship_mass_properties)
+                .after(update_throttle)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            allocate_ship_thrust
+                .after(update_ship_mass_properties)
+                .after(update_thrust_requests)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            consume_fuel
+                .after(update_power_networks)
+                .after(allocate_ship_thrust)
+                .after(update_throttle)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            engine_system
+                .after(consume_fuel)
+                .after(reset_external_forces)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            cast_ray_system
+                .after(reset_external_forces)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            apply_gravity
+                .after(reset_external_forces)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            align_docking_ports
+                .after(reset_external_forces)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            dock_ships.after(align_docking_ports).in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            undock_ships
+                .after(update_crosshair_target)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            control_rotors
+                .after(update_crosshair_target)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            update_rotor_motors
+                .after(control_rotors)
+                .in_set(SimulationSet),
+        )
+        .add_systems(simulation, update_build_mode.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            build_mode_system
+                .after(update_build_mode)
+                .in_set(SimulationSet),
+        )
+        .add_systems(simulation, undo_redo_system.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            blueprint_system
+                .after(update_crosshair_target)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            attach_modules_to_jointed_ships.in_set(SimulationSet),
+        )
+        .add_systems(simulation, update_module_joints.in_set(SimulationSet))
+        .add_systems(simulation, update_ship_layouts.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            rebuild_ship_colliders
+                .after(update_ship_layouts)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            sync_ship_graph.after(apply_damage).in_set(SimulationSet),
+        )
+        .add_systems(simulation, remove_stale_joints.in_set(SimulationSet))
+        .add_systems(simulation, update_joint_stress.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            split_disconnected_ships
+                .after(sync_ship_graph)
+                .in_set(SimulationSet),
+        )
+        .add_systems(
+            simulation,
+            enable_contact_force_events.in_set(SimulationSet),
+        )
+        .add_systems(simulation, apply_impact_damage.in_set(SimulationSet))
+        .add_systems(
+            simulation,
+            apply_damage
+                .after(apply_impact_damage)
+                .in_set(SimulationSet),
+        )
+        .add_systems(simulation, despawn_debris.in_set(SimulationSet))
+        .add_systems(Update, predict_trajectories)
+        .add_systems(
+            Update,
+            refresh_split_trajectories.before(predict_trajectories),
+        )
+        .add_systems(PostUpdate, update_sectors.after(recenter_floating_origin))
+        .add_systems(
+            PostUpdate,
+            shift_trajectories.after(recenter_floating_origin),
+        )
+        .add_systems(PostUpdate, draw_trajectories.after(shift_trajectories));
+
+    match simulation_mode.deterministic {
+        // Shifting is part of the step, so that a replay shifts at the same step
+        true => app.add_systems(
+            FixedUpdate,
+            recenter_floating_origin
+                .after(advance_simulation_input)
+                .before(SimulationSet),
+        ),
+        // Shifting has to happen between the raycasts of this frame and the physics sync
+        false => app.add_systems(
+            PostUpdate,
+            recenter_floating_origin.before(PhysicsSet::SyncBackend),
+        ),
+    };
+    app.run();
 }
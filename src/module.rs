@@ -1,5 +1,20 @@
+use std::f32::consts::PI;
+
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    damage::{ModuleArmor, ModuleHealth},
+    docking::DockingPort,
+    fuel::{Flameout, FuelTank},
+    module_definition::ModuleDefinition,
+    power::{Battery, Generator, PowerConsumer},
+    rotor::Rotor,
+    ship_controls::Throttle,
+    ship_grid::{GridPlacement, ModulePorts},
+    thruster_allocation::EngineThrottle,
+};
 
 #[derive(Component)]
 pub struct Size(pub Vec3);
@@ -9,66 +24,299 @@ pub struct ModuleTag;
 
 #[derive(Component)]
 pub struct ModuleEngineTag {
+    /// Thrust in newtons, along the module's local y axis
     pub thrust: f32,
+    /// Speed of the exhaust in meters per second
+    pub exhaust_velocity: f32,
+}
+
+/// Thrust an engine currently fires with, in newtons. Engines that need power produce
+/// no thrust while browned out.
+pub fn engine_thrust(
+    engine: &ModuleEngineTag,
+    engine_throttle: Option<&EngineThrottle>,
+    ship_throttle: Option<&Throttle>,
+    power: Option<&PowerConsumer>,
+) -> f32 {
+    if power.is_some_and(|power| !power.powered) {
+        return 0.0;
+    }
+    engine.thrust
+        * engine_throttle.map_or(1.0, |throttle| throttle.0)
+        * ship_throttle.map_or(1.0, |throttle| throttle.factor())
+}
+
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ModuleColor(pub Color);
+
+/// Mass of a module in kilograms.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ModuleMass(pub f32);
+
+/// Mass of a module, falling back to its volume when it uses the default density.
+pub fn module_mass(size: &Size, mass: Option<&ModuleMass>) -> f32 {
+    mass.map_or(size.0.x * size.0.y * size.0.z, |mass| mass.0)
+}
+
+/// What a module is built from. Densities are averages over the whole module, including
+/// its mostly hollow interior.
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleMaterial {
+    Steel,
+    Aluminium,
+    Titanium,
+    Composite,
+}
+
+impl ModuleMaterial {
+    /// Density in kilograms per cubic meter.
+    pub fn density(self) -> f32 {
+        match self {
+            ModuleMaterial::Steel => 800.0,
+            ModuleMaterial::Aluminium => 350.0,
+            ModuleMaterial::Titanium => 500.0,
+            ModuleMaterial::Composite => 250.0,
+        }
+    }
+}
+
+/// Id of the definition a module was spawned from, see `ModuleRegistry`.
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub struct ModuleDefinitionId(pub String);
+
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ModuleShape {
+    #[default]
+    Cuboid,
+    /// Uses the x component of the size as its diameter
+    Sphere,
+    /// Aligned with the y axis, uses the x component of the size as its diameter
+    Cylinder,
+}
+
+pub fn module_volume(shape: ModuleShape, size: Vec3) -> f32 {
+    let radius = size.x / 2.0;
+    match shape {
+        ModuleShape::Cuboid => size.x * size.y * size.z,
+        ModuleShape::Sphere => 4.0 / 3.0 * PI * radius.powi(3),
+        ModuleShape::Cylinder => PI * radius * radius * size.y,
+    }
+}
+
+/// Inertia tensor of a solid module around its center, in the module's local space.
+pub fn module_inertia(shape: ModuleShape, size: Vec3, mass: f32) -> Mat3 {
+    let radius = size.x / 2.0;
+    let diagonal = match shape {
+        ModuleShape::Cuboid => {
+            let squared = size * size;
+            Vec3::new(
+                squared.y + squared.z,
+                squared.x + squared.z,
+                squared.x + squared.y,
+            ) * mass
+                / 12.0
+        }
+        ModuleShape::Sphere => Vec3::splat(0.4 * mass * radius * radius),
+        ModuleShape::Cylinder => {
+            let side = mass * (3.0 * radius * radius + size.y * size.y) / 12.0;
+            Vec3::new(side, 0.5 * mass * radius * radius, side)
+        }
+    };
+    Mat3::from_diagonal(diagonal)
+}
+
+pub fn module_collider(shape: ModuleShape, size: Vec3) -> Collider {
+    match shape {
+        ModuleShape::Cuboid => Collider::cuboid(size.x / 2.0, size.y / 2.0, size.z / 2.0),
+        ModuleShape::Sphere => Collider::ball(size.x / 2.0),
+        ModuleShape::Cylinder => Collider::cylinder(size.y / 2.0, size.x / 2.0),
+    }
+}
+
+pub fn module_mesh(shape: ModuleShape, size: Vec3) -> Mesh {
+    match shape {
+        ModuleShape::Cuboid => Mesh::from(shape::Box {
+            min_x: -size.x / 2.0,
+            min_y: -size.y / 2.0,
+            min_z: -size.z / 2.0,
+            max_x: size.x / 2.0,
+            max_y: size.y / 2.0,
+            max_z: size.z / 2.0,
+        }),
+        ModuleShape::Sphere => Mesh::from(shape::UVSphere {
+            radius: size.x / 2.0,
+            ..Default::default()
+        }),
+        ModuleShape::Cylinder => Mesh::from(shape::Cylinder {
+            radius: size.x / 2.0,
+            height: size.y,
+            ..Default::default()
+        }),
+    }
 }
 
-pub fn spawn_base_module(
+fn spawn_module_entity(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
-    size: Vec3,
-    position: Vec3,
-    rotation: Quat,
-    color: Color,
-    velocity: Velocity,
+    definition: &ModuleDefinition,
+    transform: Transform,
     is_headless: bool,
 ) -> Entity {
-    if is_headless {
-        commands
-            .spawn((
-                ModuleTag,
-                Size(size),
-                SpatialBundle {
-                    transform: Transform::from_translation(position)
-                        * Transform::from_rotation(rotation),
-                    ..Default::default()
-                },
-                Collider::cuboid(size.x / 2.0, size.y / 2.0, size.z / 2.0),
-                RigidBody::Dynamic,
-                velocity,
-            ))
-            .id()
+    let mut module = if is_headless {
+        commands.spawn((
+            ModuleTag,
+            Size(definition.size),
+            definition.shape,
+            ModuleColor(definition.color),
+            ModulePorts(definition.ports.clone()),
+            SpatialBundle {
+                transform,
+                ..Default::default()
+            },
+        ))
     } else {
-        commands
-            .spawn((
-                ModuleTag,
-                Size(size),
-                PbrBundle {
-                    mesh: meshes.add(Mesh::from(shape::Box {
-                        min_x: -size.x / 2.0,
-                        min_y: -size.y / 2.0,
-                        min_z: -size.z / 2.0,
-                        max_x: size.x / 2.0,
-                        max_y: size.y / 2.0,
-                        max_z: size.z / 2.0,
-                    })),
-                    material: materials.add(color.into()),
-                    transform: Transform::from_translation(position),
-                    ..Default::default()
-                },
-                Collider::cuboid(size.x / 2.0, size.y / 2.0, size.z / 2.0),
-                RigidBody::Dynamic,
-                velocity,
-            ))
-            .id()
+        commands.spawn((
+            ModuleTag,
+            Size(definition.size),
+            definition.shape,
+            ModuleColor(definition.color),
+            ModulePorts(definition.ports.clone()),
+            PbrBundle {
+                mesh: meshes.add(module_mesh(definition.shape, definition.size)),
+                material: materials.add(definition.color.into()),
+                transform,
+                ..Default::default()
+            },
+        ))
+    };
+
+    module.insert((
+        ModuleMass(definition.initial_mass()),
+        ModuleHealth::new(definition.health),
+        ModuleArmor(definition.armor.clone()),
+    ));
+    if let Some(material) = definition.material {
+        module.insert(material);
+    }
+    if let Some(engine) = &definition.engine {
+        module.insert(ModuleEngineTag {
+            thrust: engine.thrust,
+            exhaust_velocity: engine.exhaust_velocity,
+        });
+    }
+    if let Some(fuel_tank) = &definition.fuel_tank {
+        module.insert(FuelTank {
+            capacity: fuel_tank.capacity,
+            fuel: fuel_tank.capacity,
+            dry_mass: definition.resolved_mass(),
+        });
+    }
+    if let Some(generator) = &definition.generator {
+        module.insert(Generator {
+            output: generator.output,
+        });
+    }
+    if let Some(battery) = &definition.battery {
+        module.insert(Battery {
+            capacity: battery.capacity,
+            charge: battery.capacity,
+            max_rate: battery.max_rate,
+        });
+    }
+    if let Some(power_draw) = &definition.power_draw {
+        module.insert(PowerConsumer {
+            draw: power_draw.draw,
+            priority: power_draw.priority,
+            powered: false,
+        });
+    }
+    if let Some(docking_port) = &definition.docking_port {
+        module.insert(DockingPort::new(docking_port.face, docking_port.kind));
+    }
+    if let Some(rotor) = &definition.rotor {
+        module.insert(Rotor {
+            axis: rotor.axis,
+            limits: rotor.limits,
+            max_torque: rotor.max_torque,
+            speed: rotor.speed,
+            target: rotor.target,
+        });
     }
+    module.id()
 }
 
+/// Spawns a module as part of `ship`. The module has no body or collider of its own,
+/// its shape is merged into the ship's compound collider instead.
+pub fn spawn_ship_module(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+    ship: Entity,
+    definition: &ModuleDefinition,
+    transform: Transform,
+    is_headless: bool,
+) -> Entity {
+    let module = spawn_module_entity(
+        commands,
+        meshes,
+        materials,
+        definition,
+        transform,
+        is_headless,
+    );
+    if let Some(placement) = GridPlacement::from_transform(&transform, definition.size) {
+        commands.entity(module).insert(placement);
+    }
+    commands.entity(ship).add_child(module);
+    module
+}
+
+/// The body a module's forces act on: its own body if it has one, otherwise the ship it
+/// is merged into.
+pub fn owning_body(module: Entity, has_body: bool, parent: Option<&Parent>) -> Option<Entity> {
+    match has_body {
+        true => Some(module),
+        false => parent.map(|parent| parent.get()),
+    }
+}
+
+/// Applies the thrust of every engine at the engine's position, so that engines away
+/// from the center of mass also turn the body. Forces are not scaled by the frame time,
+/// Rapier integrates them over each physics step.
 pub fn engine_system(
-    mut query: Query<(&mut Velocity, &mut ModuleEngineTag, &mut Transform)>,
-    time: Res<Time>,
+    engine_query: Query<(
+        Entity,
+        &ModuleEngineTag,
+        &GlobalTransform,
+        Option<&Parent>,
+        Option<&EngineThrottle>,
+        Option<&PowerConsumer>,
+        Has<RigidBody>,
+        Has<Flameout>,
+    )>,
+    mut body_query: Query<(&GlobalTransform, &ReadMassProperties, &mut ExternalForce)>,
+    throttle_query: Query<&Throttle>,
 ) {
-    for (mut velocity, engine, transform) in query.iter_mut() {
-        velocity.linvel += transform.rotation * Vec3::Y * engine.thrust * time.delta_seconds();
+    for (module, engine, global, parent, throttle, power, has_body, flameout) in engine_query.iter()
+    {
+        if flameout {
+            continue;
+        }
+        let Some(body) = owning_body(module, has_body, parent) else {
+            continue;
+        };
+        let Ok((body_global, mass_properties, mut external_force)) = body_query.get_mut(body)
+        else {
+            continue;
+        };
+
+        // Engines on ships follow the ship's throttle, on top of the allocated throttle
+        let ship_throttle = parent.and_then(|parent| throttle_query.get(parent.get()).ok());
+        let thrust = engine_thrust(engine, throttle, ship_throttle, power);
+        let (_, rotation, position) = global.to_scale_rotation_translation();
+        let center_of_mass = body_global.transform_point(mass_properties.local_center_of_mass);
+        *external_force +=
+            ExternalForce::at_point(rotation * Vec3::Y * thrust, position, center_of_mass);
     }
 }
@@ -0,0 +1,260 @@
+use std::{fs, path::Path};
+
+use bevy::{asset::io::file::FileAssetReader, prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    damage::DamageType,
+    module::{module_volume, spawn_ship_module, ModuleDefinitionId, ModuleMaterial, ModuleShape},
+    rotor::RotorTarget,
+    ship_grid::{default_ports, Face, PortDefinition, PortKind},
+};
+
+pub const MODULE_DEFINITION_DIR: &str = "assets/modules";
+
+/// Rapier's default collider density.
+const DEFAULT_DENSITY: f32 = 1.0;
+pub const DEFAULT_HEALTH: f32 = 100.0;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct EngineDefinition {
+    pub thrust: f32,
+    /// Speed of the exhaust in meters per second, propellant is used up at thrust divided by it
+    #[serde(default = "default_exhaust_velocity")]
+    pub exhaust_velocity: f32,
+}
+
+fn default_exhaust_velocity() -> f32 {
+    3000.0
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FuelTankDefinition {
+    /// Propellant in kilograms when full
+    pub capacity: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct GeneratorDefinition {
+    /// Power output in watts
+    pub output: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BatteryDefinition {
+    /// Stored energy in joules when full
+    pub capacity: f32,
+    /// Highest charge or discharge rate in watts
+    pub max_rate: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PowerDrawDefinition {
+    /// Power needed in watts
+    pub draw: f32,
+    /// Consumers with lower values get power first
+    #[serde(default)]
+    pub priority: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct DockingPortDefinition {
+    /// Face of the module the port is on
+    pub face: Face,
+    #[serde(default)]
+    pub kind: PortKind,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RotorDefinition {
+    /// Axis the module turns around, in the module's local space
+    #[serde(default = "default_rotor_axis")]
+    pub axis: Vec3,
+    /// Lowest and highest angle in radians, turns freely without them
+    #[serde(default)]
+    pub limits: Option<[f32; 2]>,
+    /// Highest torque of the motor in newton-meters
+    pub max_torque: f32,
+    /// Speed in radians per second when driven by the player
+    #[serde(default = "default_rotor_speed")]
+    pub speed: f32,
+    /// What the motor does right after spawning
+    #[serde(default = "default_rotor_target")]
+    pub target: RotorTarget,
+}
+
+fn default_rotor_axis() -> Vec3 {
+    Vec3::Y
+}
+
+fn default_rotor_speed() -> f32 {
+    1.0
+}
+
+fn default_rotor_target() -> RotorTarget {
+    RotorTarget::Velocity(0.0)
+}
+
+/// Describes a kind of module. Definitions are loaded from the `.ron` files in
+/// [`MODULE_DEFINITION_DIR`], where the file name is used as the definition id. Like the
+/// `AssetPlugin`, the directory is relative to the asset base path rather than the working
+/// directory.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ModuleDefinition {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub shape: ModuleShape,
+    pub size: Vec3,
+    /// Dry mass in kilograms. When missing, it is worked out from the density
+    #[serde(default)]
+    pub mass: Option<f32>,
+    /// Density in kilograms per cubic meter, overrides the density of the material
+    #[serde(default)]
+    pub density: Option<f32>,
+    #[serde(default)]
+    pub material: Option<ModuleMaterial>,
+    #[serde(default = "default_color")]
+    pub color: Color,
+    #[serde(default = "default_ports")]
+    pub ports: Vec<PortDefinition>,
+    #[serde(default)]
+    pub engine: Option<EngineDefinition>,
+    #[serde(default)]
+    pub fuel_tank: Option<FuelTankDefinition>,
+    #[serde(default)]
+    pub generator: Option<GeneratorDefinition>,
+    #[serde(default)]
+    pub battery: Option<BatteryDefinition>,
+    #[serde(default)]
+    pub power_draw: Option<PowerDrawDefinition>,
+    #[serde(default)]
+    pub docking_port: Option<DockingPortDefinition>,
+    #[serde(default)]
+    pub rotor: Option<RotorDefinition>,
+    /// Hit points of a new module
+    #[serde(default = "default_health")]
+    pub health: f32,
+    /// Damage taken off every hit, per damage type
+    #[serde(default)]
+    pub armor: HashMap<DamageType, f32>,
+}
+
+impl ModuleDefinition {
+    /// Mass in kilograms, falling back to Rapier's default density when neither a mass, a
+    /// density nor a material is given.
+    pub fn resolved_mass(&self) -> f32 {
+        self.mass.unwrap_or_else(|| {
+            let density = self
+                .density
+                .or(self.material.map(|material| material.density()))
+                .unwrap_or(DEFAULT_DENSITY);
+            density * module_volume(self.shape, self.size)
+        })
+    }
+
+    /// Mass of a freshly spawned module, including a full fuel tank.
+    pub fn initial_mass(&self) -> f32 {
+        self.resolved_mass() + self.fuel_tank.as_ref().map_or(0.0, |tank| tank.capacity)
+    }
+}
+
+fn default_color() -> Color {
+    Color::GRAY
+}
+
+fn default_health() -> f32 {
+    DEFAULT_HEALTH
+}
+
+#[derive(Resource, Default)]
+pub struct ModuleRegistry {
+    definitions: HashMap<String, ModuleDefinition>,
+}
+
+impl ModuleRegistry {
+    /// Loads every definition in `path`, relative paths are resolved from the same base path
+    /// the `AssetPlugin` uses. The files are read right away instead of through the
+    /// `AssetServer`, since the registry is inserted while the app is built and the startup
+    /// systems spawn ships from it before any asset could have finished loading.
+    pub fn load_from_dir(path: impl AsRef<Path>) -> Self {
+        let mut registry = ModuleRegistry::default();
+        let path = FileAssetReader::get_base_path().join(path);
+
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!(
+                    "Failed to read module definitions from {}: {}",
+                    path.display(),
+                    e
+                );
+                return registry;
+            }
+        };
+
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.extension().is_none_or(|extension| extension != "ron") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            match fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| ron::from_str(&text).map_err(|e| e.to_string()))
+            {
+                Ok(definition) => registry.insert(id, definition),
+                Err(e) => println!("Failed to load module definition {}: {}", id, e),
+            }
+        }
+
+        registry
+    }
+
+    pub fn insert(&mut self, id: impl Into<String>, definition: ModuleDefinition) {
+        self.definitions.insert(id.into(), definition);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModuleDefinition> {
+        self.definitions.get(id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.definitions.keys()
+    }
+
+    /// Spawns a module as part of `ship`, at `transform` relative to the ship.
+    pub fn spawn_in_ship(
+        &self,
+        id: &str,
+        commands: &mut Commands,
+        meshes: &mut ResMut<Assets<Mesh>>,
+        materials: &mut ResMut<Assets<StandardMaterial>>,
+        ship: Entity,
+        transform: Transform,
+        is_headless: bool,
+    ) -> Option<Entity> {
+        let Some(definition) = self.get(id) else {
+            println!("Unknown module definition: {}", id);
+            return None;
+        };
+        let module = spawn_ship_module(
+            commands,
+            meshes,
+            materials,
+            ship,
+            definition,
+            transform,
+            is_headless,
+        );
+        commands
+            .entity(module)
+            .insert(ModuleDefinitionId(id.to_string()));
+        Some(module)
+    }
+}
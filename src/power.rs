@@ -0,0 +1,179 @@
+use bevy::prelude::*;
+
+use crate::{ship::ShipTag, ship_graph::ShipGraph};
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Generator {
+    /// Power output in watts
+    pub output: f32,
+}
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Battery {
+    /// Stored energy in joules when full
+    pub capacity: f32,
+    /// Stored energy in joules
+    pub charge: f32,
+    /// Highest charge or discharge rate in watts
+    pub max_rate: f32,
+}
+
+/// A module that needs power to work, e.g. an engine, a light or a tool.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct PowerConsumer {
+    /// Power needed in watts
+    pub draw: f32,
+    /// Consumers with lower values get power first
+    pub priority: u32,
+    /// Cleared when the consumer is browned out
+    pub powered: bool,
+}
+
+/// Power flow of all networks of a ship during the last tick, in watts and joules.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ShipPower {
+    pub generation: f32,
+    pub demand: f32,
+    pub supplied: f32,
+    pub stored: f32,
+    pub capacity: f32,
+}
+
+/// Solves the power flow of every network, a network being a group of modules connected
+/// through joints. Generators feed the consumers in priority order, and batteries cover
+/// the shortfall or soak up the surplus. Once a consumer can't be supplied, it and every
+/// consumer after it browns out.
+pub fn update_power_networks(
+    mut commands: Commands,
+    time: Res<Time>,
+    ship_graph: Res<ShipGraph>,
+    ship_query: Query<(Entity, Option<&ShipPower>), With<ShipTag>>,
+    generator_query: Query<&Generator>,
+    mut battery_query: Query<&mut Battery>,
+    mut consumer_query: Query<(Entity, &mut PowerConsumer)>,
+) {
+    let dt = time.delta_seconds();
+    if dt <= 0.0 {
+        return;
+    }
+
+    for (ship, current) in ship_query.iter() {
+        let mut ship_power = ShipPower::default();
+
+        for network in ship_graph.connected_components(ship) {
+            let generation: f32 = network
+                .iter()
+                .filter_map(|module| generator_query.get(*module).ok())
+                .map(|generator| generator.output)
+                .sum();
+            let batteries: Vec<Entity> = network
+                .iter()
+                .copied()
+                .filter(|module| battery_query.contains(*module))
+                .collect();
+            let discharge_limit: f32 = batteries
+                .iter()
+                .filter_map(|battery| battery_query.get(*battery).ok())
+                .map(|battery| battery.max_rate.min(battery.charge / dt))
+                .sum();
+
+            let mut consumers: Vec<(Entity, u32, f32)> = network
+                .iter()
+                .filter_map(|module| consumer_query.get(*module).ok())
+                .map(|(entity, consumer)| (entity, consumer.priority, consumer.draw))
+                .collect();
+            consumers.sort_by_key(|(entity, priority, _)| (*priority, *entity));
+
+            let mut available = generation + discharge_limit;
+            let mut browned_out = false;
+            let mut supplied = 0.0;
+            for (consumer, _, draw) in consumers.iter() {
+                browned_out = browned_out || *draw > available;
+                if !browned_out {
+                    available -= draw;
+                    supplied += draw;
+                }
+                if let Ok((_, mut power_consumer)) = consumer_query.get_mut(*consumer) {
+                    if power_consumer.powered == browned_out {
+                        power_consumer.powered = !browned_out;
+                    }
+                }
+            }
+
+            // Positive when charging, negative when discharging
+            let mut battery_power = generation - supplied;
+            for battery in batteries {
+                let Ok(mut battery) = battery_query.get_mut(battery) else {
+                    continue;
+                };
+                let rate = if battery_power >= 0.0 {
+                    battery_power
+                        .min(battery.max_rate)
+                        .min((battery.capacity - battery.charge) / dt)
+                } else {
+                    battery_power
+                        .max(-battery.max_rate)
+                        .max(-battery.charge / dt)
+                };
+                if rate != 0.0 {
+                    battery.charge = (battery.charge + rate * dt).clamp(0.0, battery.capacity);
+                    battery_power -= rate;
+                }
+                ship_power.stored += battery.charge;
+                ship_power.capacity += battery.capacity;
+            }
+
+            ship_power.generation += generation;
+            ship_power.demand += consumers.iter().map(|(_, _, draw)| draw).sum::<f32>();
+            ship_power.supplied += supplied;
+        }
+
+        if current != Some(&ship_power) {
+            commands.entity(ship).insert(ship_power);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_utils::{ship_graph_app, spawn_module};
+
+    fn consumer(draw: f32, priority: u32) -> PowerConsumer {
+        PowerConsumer {
+            draw,
+            priority,
+            powered: true,
+        }
+    }
+
+    #[test]
+    fn low_priority_consumers_brown_out_first() {
+        let mut app = ship_graph_app(update_power_networks);
+        app.insert_resource(Time::<()>::default());
+
+        let ship = app.world.spawn(ShipTag).id();
+        spawn_module(&mut app.world, ship, Generator { output: 1000.0 });
+        let first = spawn_module(&mut app.world, ship, consumer(600.0, 0));
+        let second = spawn_module(&mut app.world, ship, consumer(600.0, 1));
+        // Would fit in what is left, but comes after a consumer that browned out
+        let third = spawn_module(&mut app.world, ship, consumer(100.0, 2));
+
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(1));
+        app.update();
+
+        let powered = |module| app.world.get::<PowerConsumer>(module).unwrap().powered;
+        assert!(powered(first));
+        assert!(!powered(second));
+        assert!(!powered(third));
+
+        let ship_power = app.world.get::<ShipPower>(ship).unwrap();
+        assert_eq!(ship_power.generation, 1000.0);
+        assert_eq!(ship_power.demand, 1300.0);
+        assert_eq!(ship_power.supplied, 600.0);
+    }
+}
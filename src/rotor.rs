@@ -0,0 +1,117 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{crosshair::CrosshairTarget, input::Action, simulation::SimulationInput};
+
+/// How strongly a motor pushes towards its target velocity.
+const ROTOR_VELOCITY_FACTOR: f32 = 1000.0;
+/// Stiffness and damping of a motor turning towards a target angle.
+const ROTOR_STIFFNESS: f32 = 10_000.0;
+const ROTOR_DAMPING: f32 = 1000.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum RotorTarget {
+    /// Turn at this many radians per second
+    Velocity(f32),
+    /// Turn to this angle in radians
+    Position(f32),
+}
+
+/// A module that turns relative to the module it is attached to. Ships with rotors are
+/// always kept jointed, since a single rigid body can't move.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Rotor {
+    /// Axis the module turns around, in the module's local space
+    pub axis: Vec3,
+    /// Lowest and highest angle in radians
+    pub limits: Option<[f32; 2]>,
+    /// Highest torque of the motor in newton-meters
+    pub max_torque: f32,
+    /// Speed in radians per second when driven by the player
+    pub speed: f32,
+    pub target: RotorTarget,
+}
+
+impl Rotor {
+    fn apply_motor(&self, joint: &mut GenericJoint) {
+        match self.target {
+            RotorTarget::Velocity(velocity) => {
+                joint.set_motor_velocity(JointAxis::AngX, velocity, ROTOR_VELOCITY_FACTOR)
+            }
+            RotorTarget::Position(angle) => {
+                joint.set_motor_position(JointAxis::AngX, angle, ROTOR_STIFFNESS, ROTOR_DAMPING)
+            }
+        };
+        joint.set_motor_max_force(JointAxis::AngX, self.max_torque);
+    }
+}
+
+/// Like [`crate::ship::fixed_joint_between`], but the child can turn around the rotor axis.
+pub fn rotor_joint_between(
+    parent: &Transform,
+    child: &Transform,
+    anchor: Vec3,
+    rotor: &Rotor,
+) -> GenericJoint {
+    // The free axis of the joint is the x axis of its frames, which is turned onto the rotor axis
+    let axis_basis =
+        Quat::from_rotation_arc(Vec3::X, rotor.axis.try_normalize().unwrap_or(Vec3::Y));
+    let parent_inverse = parent.rotation.inverse();
+    let mut builder = GenericJointBuilder::new(JointAxesMask::LOCKED_REVOLUTE_AXES)
+        .local_basis1(parent_inverse * child.rotation * axis_basis)
+        .local_anchor1(parent_inverse * (child.transform_point(anchor) - parent.translation))
+        .local_basis2(axis_basis)
+        .local_anchor2(anchor);
+    if let Some(limits) = rotor.limits {
+        builder = builder.limits(JointAxis::AngX, limits);
+    }
+    let mut joint = builder.build();
+    joint.set_contacts_enabled(false);
+    rotor.apply_motor(&mut joint);
+    joint
+}
+
+/// Passes changed motor targets on to the joints.
+pub fn update_rotor_motors(mut rotor_query: Query<(&Rotor, &mut ImpulseJoint), Changed<Rotor>>) {
+    for (rotor, mut joint) in rotor_query.iter_mut() {
+        rotor.apply_motor(&mut joint.data);
+    }
+}
+
+/// Lets the player turn the rotor under the crosshair, or all rotors of the ship under the
+/// crosshair. Rotors stop once the keys are released.
+pub fn control_rotors(
+    crosshair_target: Res<CrosshairTarget>,
+    input: Res<SimulationInput>,
+    mut rotor_query: Query<(Entity, &mut Rotor, Option<&Parent>)>,
+) {
+    if input.menu_open() {
+        return;
+    }
+
+    let direction =
+        input.pressed(Action::RotorForward) as i32 - input.pressed(Action::RotorBackward) as i32;
+    let released =
+        input.just_released(Action::RotorForward) || input.just_released(Action::RotorBackward);
+    if direction == 0 && !released {
+        return;
+    }
+    let targeted_module = crosshair_target
+        .module
+        .filter(|module| rotor_query.contains(*module));
+
+    for (module, mut rotor, parent) in rotor_query.iter_mut() {
+        let targeted = match targeted_module {
+            Some(targeted) => targeted == module,
+            None => parent.map(|parent| parent.get()) == crosshair_target.ship,
+        };
+        if !targeted {
+            continue;
+        }
+        let target = RotorTarget::Velocity(direction as f32 * rotor.speed);
+        if rotor.target != target {
+            rotor.target = target;
+        }
+    }
+}
@@ -5,12 +5,15 @@ use serde::{Deserialize, Serialize};
 pub struct ControlSettings {
     #[serde(default = "default_mouse_sensitivity")]
     pub mouse_sensitivity: f32,
+    #[serde(default = "default_undo_history_depth")]
+    pub undo_history_depth: usize,
 }
 
 impl Default for ControlSettings {
     fn default() -> Self {
         ControlSettings {
             mouse_sensitivity: default_mouse_sensitivity(),
+            undo_history_depth: default_undo_history_depth(),
         }
     }
 }
@@ -19,6 +22,10 @@ fn default_mouse_sensitivity() -> f32 {
     2.0
 }
 
+fn default_undo_history_depth() -> usize {
+    100
+}
+
 #[derive(Resource, Debug, Deserialize, Serialize, Clone, Copy)]
 pub struct GraphicsSettings {
     #[serde(default = "default_window_mode")]
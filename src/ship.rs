@@ -0,0 +1,633 @@
+use bevy::{prelude::*, utils::HashSet};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    joint_stress::JointStress,
+    module::{
+        module_collider, module_inertia, module_mass, ModuleMass, ModuleShape, ModuleTag, Size,
+    },
+    rotor::{rotor_joint_between, Rotor},
+    ship_controls::Throttle,
+    ship_grid::GridPlacement,
+    ship_mass::ShipMassProperties,
+    simulation::SimulationInput,
+};
+
+/// A ship is the parent of all of its modules. Depending on its [`ShipLayout`] it is either
+/// a single rigid body with one compound collider, or a set of jointed module bodies.
+#[derive(Component)]
+pub struct ShipTag;
+
+/// How a ship is represented in Rapier. Merged ships only cost one body, jointed ships
+/// give every module its own body so that nearby entities can interact with each piece.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ShipLayout {
+    #[default]
+    Merged,
+    Jointed,
+}
+
+/// The module behind each shape of a merged ship's compound collider, in the same order.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct ShipColliderModules(pub Vec<Entity>);
+
+pub const DEFAULT_BREAK_FORCE: f32 = 200_000.0;
+pub const DEFAULT_BREAK_TORQUE: f32 = 100_000.0;
+
+/// Connection from a module to the module it is attached to. It is kept while the ship
+/// is merged, so that the `ImpulseJoint` can be rebuilt when the ship is expanded.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ModuleJoint {
+    pub parent: Entity,
+    /// Attachment point in the module's local space
+    pub anchor: Vec3,
+    /// Force in newtons above which the connection breaks
+    pub break_force: f32,
+    /// Torque in newton-meters above which the connection breaks
+    pub break_torque: f32,
+}
+
+impl ModuleJoint {
+    pub fn new(parent: Entity, anchor: Vec3) -> Self {
+        ModuleJoint {
+            parent,
+            anchor,
+            break_force: DEFAULT_BREAK_FORCE,
+            break_torque: DEFAULT_BREAK_TORQUE,
+        }
+    }
+}
+
+/// Marks a `ModuleJoint` that only welds a jointless module to the root module while its
+/// ship is expanded. Both are removed again when the ship is merged.
+#[derive(Component)]
+pub struct RootWeld;
+
+/// Entities that cause nearby ships to be expanded, like the player camera or a bullet.
+#[derive(Component)]
+pub struct ProximityTrigger;
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ShipLayoutSettings {
+    /// Merged ships closer than this to a trigger are expanded
+    pub expand_radius: f32,
+    /// Jointed ships further than this from every trigger are merged back
+    pub collapse_radius: f32,
+}
+
+impl Default for ShipLayoutSettings {
+    fn default() -> Self {
+        ShipLayoutSettings {
+            expand_radius: 25.0,
+            collapse_radius: 30.0,
+        }
+    }
+}
+
+pub fn spawn_ship(commands: &mut Commands, transform: Transform, velocity: Velocity) -> Entity {
+    commands
+        .spawn((
+            ShipTag,
+            SpatialBundle {
+                transform,
+                ..Default::default()
+            },
+            ShipLayout::Merged,
+            RigidBody::Dynamic,
+            ReadMassProperties::default(),
+            ExternalForce::default(),
+            Throttle::default(),
+            velocity,
+        ))
+        .id()
+}
+
+/// Spawns a ship without a body, for modules that already have their own bodies.
+pub fn spawn_jointed_ship(commands: &mut Commands, transform: Transform) -> Entity {
+    commands
+        .spawn((
+            ShipTag,
+            SpatialBundle {
+                transform,
+                ..Default::default()
+            },
+            ShipLayout::Jointed,
+            Throttle::default(),
+        ))
+        .id()
+}
+
+/// Builds a fixed joint that holds `child` at its current transform relative to `parent`.
+/// Both transforms must be in the same space, `anchor` is in the child's local space.
+pub fn fixed_joint_between(parent: &Transform, child: &Transform, anchor: Vec3) -> GenericJoint {
+    let parent_inverse = parent.rotation.inverse();
+    let mut joint = FixedJointBuilder::new()
+        .local_basis1(parent_inverse * child.rotation)
+        .local_anchor1(parent_inverse * (child.transform_point(anchor) - parent.translation))
+        .local_anchor2(anchor)
+        .build();
+    joint.set_contacts_enabled(false);
+    joint.into()
+}
+
+/// The joint between a module and the module it is attached to. Rotors turn around their
+/// axis, everything else is fixed.
+fn module_joint_between(
+    parent: &Transform,
+    child: &Transform,
+    anchor: Vec3,
+    rotor: Option<&Rotor>,
+) -> GenericJoint {
+    match rotor {
+        Some(rotor) => rotor_joint_between(parent, child, anchor, rotor),
+        None => fixed_joint_between(parent, child, anchor),
+    }
+}
+
+pub fn rebuild_ship_colliders(
+    mut commands: Commands,
+    changed_ships: Query<Entity, (With<ShipTag>, Or<(Changed<Children>, Changed<ShipLayout>)>)>,
+    changed_modules: Query<
+        &Parent,
+        (
+            With<ModuleTag>,
+            Or<(Changed<Size>, Changed<ModuleShape>, Changed<Transform>)>,
+        ),
+    >,
+    mut emptied_ships: RemovedComponents<Children>,
+    ship_query: Query<(&ShipLayout, Option<&Children>), With<ShipTag>>,
+    module_query: Query<(&Size, &ModuleShape, &Transform), With<ModuleTag>>,
+) {
+    let mut dirty_ships: HashSet<Entity> = changed_ships.iter().collect();
+    dirty_ships.extend(changed_modules.iter().map(|parent| parent.get()));
+    dirty_ships.extend(emptied_ships.read());
+
+    for ship in dirty_ships {
+        let Ok((layout, children)) = ship_query.get(ship) else {
+            continue;
+        };
+
+        let (modules, shapes): (Vec<Entity>, Vec<(Vec3, Quat, Collider)>) = children
+            .into_iter()
+            .flatten()
+            .filter_map(|child| module_query.get(*child).ok().map(|m| (*child, m)))
+            .map(|(module, (size, shape, transform))| {
+                (
+                    module,
+                    (
+                        transform.translation,
+                        transform.rotation,
+                        module_collider(*shape, size.0),
+                    ),
+                )
+            })
+            .unzip();
+
+        if shapes.is_empty() {
+            // A ship without modules has nothing left to simulate
+            commands.entity(ship).despawn_recursive();
+        } else if *layout == ShipLayout::Merged {
+            commands
+                .entity(ship)
+                .insert((Collider::compound(shapes), ShipColliderModules(modules)));
+        }
+    }
+}
+
+type LayoutModuleComponents<'a> = (
+    &'a Transform,
+    &'a GlobalTransform,
+    &'a Size,
+    &'a ModuleShape,
+    Option<&'a ModuleMass>,
+    Option<&'a Velocity>,
+    Option<&'a ModuleJoint>,
+    Option<&'a GridPlacement>,
+    Option<&'a Rotor>,
+);
+
+pub fn update_ship_layouts(
+    mut commands: Commands,
+    settings: Res<ShipLayoutSettings>,
+    input: Res<SimulationInput>,
+    trigger_query: Query<&GlobalTransform, (With<ProximityTrigger>, Without<Camera3d>)>,
+    camera_trigger_query: Query<(), (With<ProximityTrigger>, With<Camera3d>)>,
+    ship_query: Query<
+        (
+            Entity,
+            &ShipLayout,
+            &Children,
+            &GlobalTransform,
+            Option<&Velocity>,
+            Option<&ReadMassProperties>,
+        ),
+        With<ShipTag>,
+    >,
+    module_query: Query<LayoutModuleComponents, With<ModuleTag>>,
+    weld_query: Query<(), With<RootWeld>>,
+) {
+    // The camera counts where the simulation sees it, so that replays expand the same ships
+    let triggers: Vec<Vec3> = trigger_query
+        .iter()
+        .map(|t| t.translation())
+        .chain((!camera_trigger_query.is_empty()).then_some(input.camera().translation))
+        .collect();
+
+    for (ship, layout, children, ship_transform, velocity, mass) in ship_query.iter() {
+        let modules: Vec<(Entity, LayoutModuleComponents)> = children
+            .iter()
+            .filter_map(|child| module_query.get(*child).ok().map(|m| (*child, m)))
+            .collect();
+
+        let distance = modules
+            .iter()
+            .flat_map(|(_, (_, global, ..))| {
+                triggers
+                    .iter()
+                    .map(|trigger| global.translation().distance(*trigger))
+            })
+            .fold(f32::INFINITY, f32::min);
+
+        // A single body can't turn its rotors
+        let has_rotor = modules.iter().any(|(_, (.., rotor))| rotor.is_some());
+
+        match layout {
+            ShipLayout::Merged if distance < settings.expand_radius || has_rotor => {
+                let center_of_mass = mass.map_or(Vec3::ZERO, |m| m.local_center_of_mass);
+                expand_ship(
+                    &mut commands,
+                    ship,
+                    ship_transform.transform_point(center_of_mass),
+                    velocity.copied().unwrap_or_default(),
+                    &modules,
+                    &module_query,
+                );
+            }
+            ShipLayout::Jointed if distance > settings.collapse_radius && !has_rotor => {
+                collapse_ship(&mut commands, ship, &modules, &weld_query);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The module the other modules are jointed to when they have no `ModuleJoint` of their own.
+fn root_module(modules: &[(Entity, LayoutModuleComponents)]) -> Option<Entity> {
+    modules
+        .iter()
+        .find(|(_, (.., joint, _, _))| joint.is_none())
+        .or(modules.first())
+        .map(|(entity, _)| *entity)
+}
+
+/// Gives a module of a jointed ship its own body and collider.
+fn insert_module_body(
+    commands: &mut Commands,
+    module: Entity,
+    shape: ModuleShape,
+    size: &Size,
+    mass: Option<&ModuleMass>,
+    velocity: Velocity,
+) {
+    let mut module_commands = commands.entity(module);
+    module_commands.insert((
+        RigidBody::Dynamic,
+        module_collider(shape, size.0),
+        velocity,
+        ReadMassProperties::default(),
+        ExternalForce::default(),
+    ));
+    if let Some(mass) = mass {
+        module_commands.insert(ColliderMassProperties::Mass(mass.0));
+    }
+}
+
+/// Gives every module its own body, keeping the velocity each point had on the merged body.
+fn expand_ship(
+    commands: &mut Commands,
+    ship: Entity,
+    center_of_mass: Vec3,
+    velocity: Velocity,
+    modules: &[(Entity, LayoutModuleComponents)],
+    module_query: &Query<LayoutModuleComponents, With<ModuleTag>>,
+) {
+    let root = root_module(modules);
+
+    for (module, (transform, global, size, shape, mass, _, joint, _, rotor)) in modules.iter() {
+        let offset = global.translation() - center_of_mass;
+        insert_module_body(
+            commands,
+            *module,
+            **shape,
+            size,
+            *mass,
+            Velocity {
+                linvel: velocity.linvel + velocity.angvel.cross(offset),
+                angvel: velocity.angvel,
+            },
+        );
+
+        let joint = match (joint, root) {
+            (Some(joint), _) => **joint,
+            (None, Some(root)) if root != *module => {
+                // Give the weld a joint of its own, so that its stress is tracked and it can
+                // break like any other connection
+                let joint = ModuleJoint::new(root, Vec3::ZERO);
+                commands.entity(*module).insert((joint, RootWeld));
+                joint
+            }
+            _ => continue,
+        };
+        if let Ok((parent_transform, ..)) = module_query.get(joint.parent) {
+            commands.entity(*module).insert(ImpulseJoint::new(
+                joint.parent,
+                module_joint_between(parent_transform, transform, joint.anchor, *rotor),
+            ));
+        }
+    }
+
+    commands
+        .entity(ship)
+        .remove::<(
+            RigidBody,
+            Collider,
+            ShipColliderModules,
+            Velocity,
+            ExternalForce,
+        )>()
+        .insert(ShipLayout::Jointed);
+}
+
+/// Velocity of a single body that has the same momentum and angular momentum as `bodies`,
+/// given as their center of mass, inertia, mass and velocity. The merged body has its center
+/// of mass at `center_of_mass` and `inertia` around it, everything in world space.
+fn merged_velocity(
+    bodies: impl IntoIterator<Item = (Vec3, Mat3, f32, Velocity)>,
+    center_of_mass: Vec3,
+    inertia: Mat3,
+) -> Velocity {
+    let mut total_mass = 0.0;
+    let mut momentum = Vec3::ZERO;
+    let mut angular_momentum = Vec3::ZERO;
+    for (position, body_inertia, mass, velocity) in bodies {
+        total_mass += mass;
+        momentum += mass * velocity.linvel;
+        angular_momentum += body_inertia * velocity.angvel
+            + (position - center_of_mass).cross(mass * velocity.linvel);
+    }
+
+    Velocity {
+        linvel: match total_mass > 0.0 {
+            true => momentum / total_mass,
+            false => Vec3::ZERO,
+        },
+        angvel: match inertia.determinant() > 0.0 {
+            true => inertia.inverse() * angular_momentum,
+            false => Vec3::ZERO,
+        },
+    }
+}
+
+/// Moves the ship body onto the root module and merges all modules back into it. The
+/// merged body keeps the total momentum and angular momentum of the modules.
+fn collapse_ship(
+    commands: &mut Commands,
+    ship: Entity,
+    modules: &[(Entity, LayoutModuleComponents)],
+    weld_query: &Query<(), With<RootWeld>>,
+) {
+    let Some(root) = root_module(modules) else {
+        return;
+    };
+    let Some((_, (_, root_global, _, _, _, _, _, root_placement, _))) =
+        modules.iter().find(|(entity, _)| *entity == root)
+    else {
+        return;
+    };
+
+    // Modules placed on the grid go back to their designed poses, so that the grid stays
+    // aligned with the ship even though the modules moved around while jointed
+    let ship_transform = match root_placement {
+        Some(placement) => Transform::from_matrix(
+            root_global.compute_matrix() * placement.transform().compute_matrix().inverse(),
+        ),
+        None => root_global.compute_transform(),
+    };
+    let ship_global = GlobalTransform::from(ship_transform);
+
+    let local_transforms: Vec<Transform> = modules
+        .iter()
+        .map(|(_, (_, global, .., placement, _))| match placement {
+            Some(placement) => placement.transform(),
+            None => global.reparented_to(&ship_global),
+        })
+        .collect();
+    let mass_properties =
+        ShipMassProperties::from_modules(modules.iter().zip(local_transforms.iter()).map(
+            |((_, (_, _, size, shape, mass, ..)), transform)| {
+                (*transform, *shape, *size, module_mass(size, *mass))
+            },
+        ));
+    let ship_rotation = Mat3::from_quat(ship_transform.rotation);
+    let velocity = merged_velocity(
+        modules
+            .iter()
+            .map(|(_, (_, global, size, shape, mass, velocity, ..))| {
+                let mass = module_mass(size, *mass);
+                let (_, rotation, position) = global.to_scale_rotation_translation();
+                let rotation = Mat3::from_quat(rotation);
+                (
+                    position,
+                    rotation * module_inertia(**shape, size.0, mass) * rotation.transpose(),
+                    mass,
+                    velocity.copied().unwrap_or_default(),
+                )
+            }),
+        ship_global.transform_point(mass_properties.local_center_of_mass),
+        ship_rotation * mass_properties.inertia * ship_rotation.transpose(),
+    );
+
+    for ((module, (.., joint, _, _)), transform) in modules.iter().zip(local_transforms) {
+        if weld_query.contains(*module) {
+            let mut module_commands = commands.entity(*module);
+            module_commands.remove::<(RootWeld, JointStress)>();
+            // The weld may have been replaced by a real joint in the meantime
+            if joint.is_some_and(|joint| joint.parent == root) {
+                module_commands.remove::<ModuleJoint>();
+            }
+        }
+        commands
+            .entity(*module)
+            .remove::<(
+                RigidBody,
+                Collider,
+                ColliderMassProperties,
+                Velocity,
+                ExternalForce,
+                ReadMassProperties,
+                ImpulseJoint,
+            )>()
+            .insert(transform);
+    }
+
+    commands.entity(ship).insert((
+        ship_transform,
+        ShipLayout::Merged,
+        RigidBody::Dynamic,
+        ExternalForce::default(),
+        velocity,
+    ));
+}
+
+/// Modules added to a jointed ship, e.g. by the build mode, start out without a body.
+/// This gives them one and joints them to the module they are attached to.
+pub fn attach_modules_to_jointed_ships(
+    mut commands: Commands,
+    new_modules: Query<
+        (
+            Entity,
+            &Parent,
+            &Transform,
+            &Size,
+            &ModuleShape,
+            Option<&ModuleMass>,
+            Option<&ModuleJoint>,
+            Option<&Rotor>,
+        ),
+        (With<ModuleTag>, Without<RigidBody>, Changed<Parent>),
+    >,
+    ship_query: Query<&ShipLayout, With<ShipTag>>,
+    body_query: Query<(&Transform, Option<&Velocity>), With<ModuleTag>>,
+) {
+    for (module, parent, transform, size, shape, mass, joint, rotor) in new_modules.iter() {
+        if !matches!(ship_query.get(parent.get()), Ok(ShipLayout::Jointed)) {
+            continue;
+        }
+
+        let mut velocity = Velocity::zero();
+        if let Some(joint) = joint {
+            if let Ok((parent_transform, parent_velocity)) = body_query.get(joint.parent) {
+                velocity = parent_velocity.copied().unwrap_or_default();
+                commands.entity(module).insert(ImpulseJoint::new(
+                    joint.parent,
+                    module_joint_between(parent_transform, transform, joint.anchor, rotor),
+                ));
+            }
+        }
+        insert_module_body(&mut commands, module, *shape, size, mass, velocity);
+    }
+}
+
+/// Rebuilds the `ImpulseJoint` of modules with their own body when their `ModuleJoint`
+/// changes, e.g. when they are jointed to another module in build mode.
+pub fn update_module_joints(
+    mut commands: Commands,
+    module_query: Query<
+        (Entity, &Transform, &ModuleJoint, Option<&Rotor>),
+        (With<ModuleTag>, With<RigidBody>, Changed<ModuleJoint>),
+    >,
+    body_query: Query<&Transform, (With<ModuleTag>, With<RigidBody>)>,
+) {
+    for (module, transform, joint, rotor) in module_query.iter() {
+        if let Ok(parent_transform) = body_query.get(joint.parent) {
+            commands.entity(module).insert(ImpulseJoint::new(
+                joint.parent,
+                module_joint_between(parent_transform, transform, joint.anchor, rotor),
+            ));
+        }
+    }
+}
+
+/// Moves modules from another ship onto `ship`. Modules placed on the grid go back to
+/// their designed poses, the rest keep their current world transform.
+pub fn move_modules_to_ship<'a>(
+    commands: &mut Commands,
+    ship: Entity,
+    ship_global: &GlobalTransform,
+    modules: impl IntoIterator<Item = (Entity, &'a GlobalTransform, Option<&'a GridPlacement>)>,
+) {
+    for (module, global, placement) in modules {
+        commands
+            .entity(module)
+            .remove::<(
+                RigidBody,
+                Collider,
+                ColliderMassProperties,
+                Velocity,
+                ExternalForce,
+                ReadMassProperties,
+                ImpulseJoint,
+            )>()
+            .insert(match placement {
+                Some(placement) => placement.transform(),
+                None => global.reparented_to(ship_global),
+            });
+        commands.entity(ship).add_child(module);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::spawn_module;
+
+    #[test]
+    fn layout_round_trip_keeps_velocity() {
+        let mut app = App::new();
+        app.insert_resource(ShipLayoutSettings::default())
+            .insert_resource(SimulationInput::default())
+            .add_systems(Update, update_ship_layouts);
+
+        let velocity = Velocity {
+            linvel: Vec3::new(1.0, 2.0, 3.0),
+            angvel: Vec3::new(0.1, 0.5, -0.2),
+        };
+        let ship = app
+            .world
+            .spawn((
+                ShipTag,
+                ShipLayout::Merged,
+                RigidBody::Dynamic,
+                velocity,
+                TransformBundle::default(),
+            ))
+            .id();
+        // Arranged around the ship's origin, so that it is also the center of mass
+        for position in [
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::new(0.0, 1.0, 1.0),
+            Vec3::new(0.0, -1.0, -1.0),
+        ] {
+            spawn_module(
+                &mut app.world,
+                ship,
+                (
+                    Size(Vec3::ONE),
+                    ModuleShape::Cuboid,
+                    TransformBundle::from_transform(Transform::from_translation(position)),
+                ),
+            );
+        }
+
+        let trigger = app
+            .world
+            .spawn((ProximityTrigger, TransformBundle::default()))
+            .id();
+        app.update();
+        assert_eq!(
+            app.world.get::<ShipLayout>(ship),
+            Some(&ShipLayout::Jointed)
+        );
+
+        app.world
+            .entity_mut(trigger)
+            .insert(GlobalTransform::from_translation(Vec3::X * 1000.0));
+        app.update();
+        assert_eq!(app.world.get::<ShipLayout>(ship), Some(&ShipLayout::Merged));
+
+        let merged = app.world.get::<Velocity>(ship).unwrap();
+        assert!(merged.linvel.abs_diff_eq(velocity.linvel, 1e-4));
+        assert!(merged.angvel.abs_diff_eq(velocity.angvel, 1e-4));
+    }
+}
@@ -0,0 +1,67 @@
+use bevy::prelude::*;
+
+use crate::{crosshair::CrosshairTarget, input::Action, simulation::SimulationInput};
+
+/// How much the throttle changes per second while throttle up or down is held.
+const THROTTLE_RATE: f32 = 0.5;
+
+/// Throttle of a whole ship, scales the thrust of all of its engines.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Throttle {
+    /// Between 0 and 1
+    pub level: f32,
+    pub engines_on: bool,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle {
+            level: 0.0,
+            engines_on: true,
+        }
+    }
+}
+
+impl Throttle {
+    /// Fraction of full thrust the engines fire with.
+    pub fn factor(&self) -> f32 {
+        match self.engines_on {
+            true => self.level,
+            false => 0.0,
+        }
+    }
+}
+
+/// Lets the player control the throttle of the ship under the crosshair.
+pub fn update_throttle(
+    time: Res<Time>,
+    crosshair_target: Res<CrosshairTarget>,
+    input: Res<SimulationInput>,
+    mut throttle_query: Query<&mut Throttle>,
+) {
+    if input.menu_open() {
+        return;
+    }
+    let Some(mut throttle) = crosshair_target
+        .ship
+        .and_then(|ship| throttle_query.get_mut(ship).ok())
+    else {
+        return;
+    };
+
+    let direction =
+        input.pressed(Action::ThrottleUp) as i32 - input.pressed(Action::ThrottleDown) as i32;
+    if direction != 0 {
+        throttle.level = (throttle.level + direction as f32 * THROTTLE_RATE * time.delta_seconds())
+            .clamp(0.0, 1.0);
+    }
+    if input.just_pressed(Action::ThrottleCut) {
+        throttle.level = 0.0;
+    }
+    if input.just_pressed(Action::FullThrottle) {
+        throttle.level = 1.0;
+    }
+    if input.just_pressed(Action::ToggleEngines) {
+        throttle.engines_on = !throttle.engines_on;
+    }
+}
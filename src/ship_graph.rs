@@ -0,0 +1,433 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    module::ModuleTag,
+    ship::{ModuleJoint, RootWeld, ShipTag},
+};
+
+/// Tracks which modules are jointed together and which ship each module belongs to.
+/// The `ModuleJoint` components are the source of truth, this resource mirrors them so
+/// that ship-level systems can query connectivity without walking the hierarchy. Modules
+/// without a `ModuleJoint` are welded to the ship's root module when it is expanded, so
+/// all of them count as connected to each other, unless they just lost their joint.
+#[derive(Resource, Default)]
+pub struct ShipGraph {
+    neighbors: HashMap<Entity, HashSet<Entity>>,
+    /// The module each module is jointed to
+    joint_parents: HashMap<Entity, Entity>,
+    module_ships: HashMap<Entity, Entity>,
+    ship_modules: HashMap<Entity, HashSet<Entity>>,
+    /// Ships that lost a module or a joint since the last call to `take_dirty_ships`
+    dirty_ships: HashSet<Entity>,
+    /// Modules that lost their joint, they are not welded to the root until they move to
+    /// another ship
+    detached: HashSet<Entity>,
+    /// Connected components of each ship as of the last call to `refresh_components`
+    components: HashMap<Entity, Vec<Vec<Entity>>>,
+    /// Index of each module's component in `components`
+    module_components: HashMap<Entity, usize>,
+    /// Ships whose cached components are out of date
+    stale_ships: HashSet<Entity>,
+}
+
+impl ShipGraph {
+    pub fn contains(&self, module: Entity) -> bool {
+        self.neighbors.contains_key(&module)
+    }
+
+    pub fn add_module(&mut self, module: Entity, ship: Option<Entity>) {
+        self.neighbors.entry(module).or_default();
+        self.set_ship(module, ship);
+    }
+
+    pub fn remove_module(&mut self, module: Entity) {
+        self.mark_dirty(module);
+        self.remove_joint(module);
+        self.set_ship(module, None);
+        if let Some(neighbors) = self.neighbors.remove(&module) {
+            for neighbor in neighbors {
+                self.mark_stale(neighbor);
+                if let Some(set) = self.neighbors.get_mut(&neighbor) {
+                    set.remove(&module);
+                }
+                if self.joint_parents.get(&neighbor) == Some(&module) {
+                    self.joint_parents.remove(&neighbor);
+                    self.detached.insert(neighbor);
+                }
+            }
+        }
+    }
+
+    pub fn set_ship(&mut self, module: Entity, ship: Option<Entity>) {
+        self.mark_stale(module);
+        self.detached.remove(&module);
+        if let Some(old_ship) = self.module_ships.remove(&module) {
+            if let Some(modules) = self.ship_modules.get_mut(&old_ship) {
+                modules.remove(&module);
+                if modules.is_empty() {
+                    self.ship_modules.remove(&old_ship);
+                }
+            }
+        }
+        if let Some(ship) = ship {
+            self.module_ships.insert(module, ship);
+            self.ship_modules.entry(ship).or_default().insert(module);
+        }
+        self.mark_stale(module);
+    }
+
+    pub fn set_joint(&mut self, child: Entity, parent: Entity) {
+        self.remove_joint(child);
+        self.detached.remove(&child);
+        self.joint_parents.insert(child, parent);
+        self.neighbors.entry(child).or_default().insert(parent);
+        self.neighbors.entry(parent).or_default().insert(child);
+        self.mark_stale(child);
+        self.mark_stale(parent);
+    }
+
+    pub fn remove_joint(&mut self, child: Entity) {
+        if self.unlink(child) {
+            self.mark_dirty(child);
+            self.detached.insert(child);
+        }
+    }
+
+    /// Removes a joint that only welded `child` to its ship's root, the module stays
+    /// welded to the root without it.
+    pub fn remove_weld(&mut self, child: Entity) {
+        self.unlink(child);
+    }
+
+    /// Removes the edge to the module `child` is jointed to, returns whether it had a joint.
+    fn unlink(&mut self, child: Entity) -> bool {
+        let Some(parent) = self.joint_parents.remove(&child) else {
+            return false;
+        };
+        self.mark_stale(child);
+        self.mark_stale(parent);
+        // Keep the edge if the two modules are also jointed the other way around
+        if self.joint_parents.get(&parent) != Some(&child) {
+            if let Some(set) = self.neighbors.get_mut(&child) {
+                set.remove(&parent);
+            }
+            if let Some(set) = self.neighbors.get_mut(&parent) {
+                set.remove(&child);
+            }
+        }
+        true
+    }
+
+    fn mark_dirty(&mut self, module: Entity) {
+        if let Some(ship) = self.ship_of(module) {
+            self.dirty_ships.insert(ship);
+        }
+    }
+
+    fn mark_stale(&mut self, module: Entity) {
+        if let Some(ship) = self.ship_of(module) {
+            self.stale_ships.insert(ship);
+        }
+    }
+
+    pub fn take_dirty_ships(&mut self) -> Vec<Entity> {
+        self.dirty_ships.drain().collect()
+    }
+
+    pub fn ship_of(&self, module: Entity) -> Option<Entity> {
+        self.module_ships.get(&module).copied()
+    }
+
+    pub fn modules_of_ship(&self, ship: Entity) -> Vec<Entity> {
+        self.ship_modules
+            .get(&ship)
+            .map(|modules| modules.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn neighbors(&self, module: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.neighbors.get(&module).into_iter().flatten().copied()
+    }
+
+    /// Whether `module` is welded to its ship's root module without a joint of its own.
+    fn is_welded_to_root(&self, module: Entity) -> bool {
+        !self.joint_parents.contains_key(&module) && !self.detached.contains(&module)
+    }
+
+    fn welded_modules(&self, ship: Option<Entity>) -> Vec<Entity> {
+        ship.and_then(|ship| self.ship_modules.get(&ship))
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|module| self.is_welded_to_root(*module))
+            .collect()
+    }
+
+    /// Walks through joints from `start` without leaving `ship`, and returns the sorted
+    /// modules that weren't `visited` yet. Reaching one of the `welded` modules reaches
+    /// all of them.
+    fn walk(
+        &self,
+        start: Entity,
+        ship: Option<Entity>,
+        welded: &[Entity],
+        visited: &mut HashSet<Entity>,
+    ) -> Vec<Entity> {
+        let mut component = Vec::new();
+        if !visited.insert(start) {
+            return component;
+        }
+
+        let mut queue = VecDeque::from([start]);
+        let mut welded_reached = false;
+        while let Some(current) = queue.pop_front() {
+            component.push(current);
+            let siblings: &[Entity] = match !welded_reached && self.is_welded_to_root(current) {
+                true => {
+                    welded_reached = true;
+                    welded
+                }
+                false => &[],
+            };
+            for neighbor in self.neighbors(current).chain(siblings.iter().copied()) {
+                if self.ship_of(neighbor) == ship && visited.insert(neighbor) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        component.sort();
+        component
+    }
+
+    fn compute_components(&self, ship: Entity) -> Vec<Vec<Entity>> {
+        let mut modules = self.modules_of_ship(ship);
+        modules.sort();
+        let welded = self.welded_modules(Some(ship));
+        let mut visited = HashSet::new();
+        modules
+            .into_iter()
+            .map(|module| self.walk(module, Some(ship), &welded, &mut visited))
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+
+    fn cached_component(&self, module: Entity) -> Option<&Vec<Entity>> {
+        let ship = self.ship_of(module)?;
+        if self.stale_ships.contains(&ship) {
+            return None;
+        }
+        self.components
+            .get(&ship)?
+            .get(*self.module_components.get(&module)?)
+    }
+
+    /// Recomputes the cached components of every ship that changed since the last call.
+    pub fn refresh_components(&mut self) {
+        let stale_ships: Vec<Entity> = self.stale_ships.drain().collect();
+        for ship in stale_ships.iter() {
+            for module in self.components.remove(ship).into_iter().flatten().flatten() {
+                self.module_components.remove(&module);
+            }
+        }
+        for ship in stale_ships {
+            let components = self.compute_components(ship);
+            if components.is_empty() {
+                continue;
+            }
+            for (index, component) in components.iter().enumerate() {
+                for module in component.iter() {
+                    self.module_components.insert(*module, index);
+                }
+            }
+            self.components.insert(ship, components);
+        }
+    }
+
+    /// All modules of the same ship that can be reached from `module` through joints,
+    /// including itself, sorted.
+    pub fn connected_modules(&self, module: Entity) -> Vec<Entity> {
+        if let Some(component) = self.cached_component(module) {
+            return component.clone();
+        }
+        if !self.contains(module) {
+            return Vec::new();
+        }
+        let ship = self.ship_of(module);
+        self.walk(
+            module,
+            ship,
+            &self.welded_modules(ship),
+            &mut HashSet::new(),
+        )
+    }
+
+    /// Splits the modules of `ship` into groups that are connected through joints. Each
+    /// group is sorted, and the groups are ordered by their first module.
+    pub fn connected_components(&self, ship: Entity) -> Vec<Vec<Entity>> {
+        match self.components.get(&ship) {
+            Some(components) if !self.stale_ships.contains(&ship) => components.clone(),
+            _ => self.compute_components(ship),
+        }
+    }
+}
+
+pub fn sync_ship_graph(
+    mut ship_graph: ResMut<ShipGraph>,
+    mut removed_modules: RemovedComponents<ModuleTag>,
+    mut removed_joints: RemovedComponents<ModuleJoint>,
+    mut removed_welds: RemovedComponents<RootWeld>,
+    mut removed_parents: RemovedComponents<Parent>,
+    changed_modules: Query<(Entity, Option<&Parent>), (With<ModuleTag>, Changed<Parent>)>,
+    added_modules: Query<(Entity, Option<&Parent>), Added<ModuleTag>>,
+    changed_joints: Query<(Entity, &ModuleJoint), (With<ModuleTag>, Changed<ModuleJoint>)>,
+    ship_query: Query<(), With<ShipTag>>,
+) {
+    let ship_of = |parent: Option<&Parent>| {
+        parent
+            .map(|parent| parent.get())
+            .filter(|parent| ship_query.contains(*parent))
+    };
+
+    let removed_welds: HashSet<Entity> = removed_welds.read().collect();
+    for module in removed_joints.read() {
+        match removed_welds.contains(&module) {
+            true => ship_graph.remove_weld(module),
+            false => ship_graph.remove_joint(module),
+        }
+    }
+    for module in removed_modules.read() {
+        ship_graph.remove_module(module);
+    }
+    for module in removed_parents.read() {
+        if ship_graph.contains(module) {
+            ship_graph.set_ship(module, None);
+        }
+    }
+
+    for (module, parent) in added_modules.iter().chain(changed_modules.iter()) {
+        ship_graph.add_module(module, ship_of(parent));
+    }
+    for (module, joint) in changed_joints.iter() {
+        ship_graph.set_joint(module, joint.parent);
+    }
+    ship_graph.refresh_components();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities<const N: usize>(start: u32) -> [Entity; N] {
+        std::array::from_fn(|index| Entity::from_raw(start + index as u32))
+    }
+
+    #[test]
+    fn jointless_modules_are_welded_together() {
+        let [ship] = entities(0);
+        let [a, b, c] = entities(1);
+        let mut graph = ShipGraph::default();
+        for module in [a, b, c] {
+            graph.add_module(module, Some(ship));
+        }
+
+        assert!(graph.contains(a));
+        assert_eq!(graph.ship_of(b), Some(ship));
+        assert_eq!(graph.connected_modules(a), vec![a, b, c]);
+        assert_eq!(graph.connected_components(ship), vec![vec![a, b, c]]);
+    }
+
+    #[test]
+    fn joints_connect_modules() {
+        let [ship] = entities(0);
+        let [root, a, b] = entities(1);
+        let mut graph = ShipGraph::default();
+        for module in [root, a, b] {
+            graph.add_module(module, Some(ship));
+        }
+        graph.set_joint(a, root);
+        graph.set_joint(b, a);
+        graph.refresh_components();
+
+        assert_eq!(graph.neighbors(a).count(), 2);
+        assert_eq!(graph.connected_modules(b), vec![root, a, b]);
+        assert_eq!(graph.connected_components(ship), vec![vec![root, a, b]]);
+        assert!(graph.take_dirty_ships().is_empty());
+    }
+
+    #[test]
+    fn removed_joint_detaches_the_module() {
+        let [ship] = entities(0);
+        let [root, a, b] = entities(1);
+        let mut graph = ShipGraph::default();
+        for module in [root, a, b] {
+            graph.add_module(module, Some(ship));
+        }
+        graph.set_joint(a, root);
+        graph.set_joint(b, a);
+        graph.refresh_components();
+
+        // a is jointless now, but it broke off instead of being welded to the root
+        graph.remove_joint(a);
+        assert_eq!(graph.take_dirty_ships(), vec![ship]);
+        assert_eq!(
+            graph.connected_components(ship),
+            vec![vec![root], vec![a, b]]
+        );
+        graph.refresh_components();
+        assert_eq!(graph.connected_modules(b), vec![a, b]);
+
+        // Moving to another ship welds it to that ship's root again
+        let [other_ship, other_root] = entities(10);
+        graph.add_module(other_root, Some(other_ship));
+        graph.set_ship(a, Some(other_ship));
+        graph.set_ship(b, Some(other_ship));
+        graph.refresh_components();
+        assert_eq!(graph.connected_components(ship), vec![vec![root]]);
+        assert_eq!(
+            graph.connected_components(other_ship),
+            vec![vec![a, b, other_root]]
+        );
+    }
+
+    #[test]
+    fn removed_weld_keeps_the_module_welded() {
+        let [ship] = entities(0);
+        let [root, a] = entities(1);
+        let mut graph = ShipGraph::default();
+        graph.add_module(root, Some(ship));
+        graph.add_module(a, Some(ship));
+        graph.set_joint(a, root);
+
+        graph.remove_weld(a);
+        assert!(graph.take_dirty_ships().is_empty());
+        assert_eq!(graph.connected_components(ship), vec![vec![root, a]]);
+    }
+
+    #[test]
+    fn removed_module_splits_the_ship() {
+        let [ship] = entities(0);
+        let [root, a, b] = entities(1);
+        let mut graph = ShipGraph::default();
+        for module in [root, a, b] {
+            graph.add_module(module, Some(ship));
+        }
+        graph.set_joint(a, root);
+        graph.set_joint(b, a);
+        graph.refresh_components();
+
+        graph.remove_module(a);
+        graph.refresh_components();
+        assert!(!graph.contains(a));
+        assert_eq!(graph.ship_of(a), None);
+        assert_eq!(graph.neighbors(root).count(), 0);
+        assert_eq!(graph.take_dirty_ships(), vec![ship]);
+        // b lost the module it was jointed to, so it isn't welded to the root either
+        assert_eq!(graph.connected_components(ship), vec![vec![root], vec![b]]);
+        assert_eq!(graph.connected_modules(b), vec![b]);
+    }
+}
@@ -0,0 +1,336 @@
+use std::fmt;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{module_definition::ModuleDefinition, ship::ModuleJoint};
+
+/// Edge length of one cell of the ship-local grid.
+pub const GRID_CELL_SIZE: f32 = 1.0;
+
+/// Tolerance used when checking if a transform lines up with the grid.
+const GRID_EPSILON: f32 = 1e-3;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::PosX,
+        Face::NegX,
+        Face::PosY,
+        Face::NegY,
+        Face::PosZ,
+        Face::NegZ,
+    ];
+
+    pub fn normal(self) -> Vec3 {
+        match self {
+            Face::PosX => Vec3::X,
+            Face::NegX => Vec3::NEG_X,
+            Face::PosY => Vec3::Y,
+            Face::NegY => Vec3::NEG_Y,
+            Face::PosZ => Vec3::Z,
+            Face::NegZ => Vec3::NEG_Z,
+        }
+    }
+
+    /// The face whose normal is closest to `direction`.
+    pub fn from_direction(direction: Vec3) -> Face {
+        let abs = direction.abs();
+        if abs.x >= abs.y && abs.x >= abs.z {
+            if direction.x >= 0.0 {
+                Face::PosX
+            } else {
+                Face::NegX
+            }
+        } else if abs.y >= abs.z {
+            if direction.y >= 0.0 {
+                Face::PosY
+            } else {
+                Face::NegY
+            }
+        } else if direction.z >= 0.0 {
+            Face::PosZ
+        } else {
+            Face::NegZ
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PortKind {
+    #[default]
+    Standard,
+    Small,
+    /// Connects to any other kind of port
+    Universal,
+}
+
+impl PortKind {
+    pub fn is_compatible(self, other: PortKind) -> bool {
+        self == other || self == PortKind::Universal || other == PortKind::Universal
+    }
+}
+
+/// An attachment port in the center of one of the module's faces.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PortDefinition {
+    pub face: Face,
+    #[serde(default)]
+    pub kind: PortKind,
+}
+
+impl PortDefinition {
+    /// Position of the port in the module's local space.
+    pub fn local_position(&self, size: Vec3) -> Vec3 {
+        self.face.normal() * size / 2.0
+    }
+}
+
+/// A standard port on every face.
+pub fn default_ports() -> Vec<PortDefinition> {
+    Face::ALL
+        .iter()
+        .map(|face| PortDefinition {
+            face: *face,
+            kind: PortKind::Standard,
+        })
+        .collect()
+}
+
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct ModulePorts(pub Vec<PortDefinition>);
+
+/// Cells a module occupies on its ship's grid, and how it is rotated. This is the pose the
+/// module was designed with, which stays valid while the ship is jointed and moving.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct GridPlacement {
+    /// Lowest occupied cell
+    pub origin: IVec3,
+    /// Number of occupied cells along each axis
+    pub extent: IVec3,
+    /// Multiple of quarter turns around the grid axes
+    pub rotation: Quat,
+}
+
+impl GridPlacement {
+    fn extent_of(size: Vec3, rotation: Quat) -> IVec3 {
+        ((rotation * size).abs() / GRID_CELL_SIZE)
+            .round()
+            .as_ivec3()
+            .max(IVec3::ONE)
+    }
+
+    /// The placement whose center is closest to `center`.
+    pub fn snapped(center: Vec3, size: Vec3, rotation: Quat) -> Self {
+        let extent = Self::extent_of(size, rotation);
+        let origin = (center / GRID_CELL_SIZE - (extent - IVec3::ONE).as_vec3() / 2.0)
+            .round()
+            .as_ivec3();
+        GridPlacement {
+            origin,
+            extent,
+            rotation,
+        }
+    }
+
+    /// The placement matching `transform`, if it lines up with the grid.
+    pub fn from_transform(transform: &Transform, size: Vec3) -> Option<Self> {
+        let placement = Self::snapped(transform.translation, size, transform.rotation);
+        let on_grid = placement
+            .center()
+            .abs_diff_eq(transform.translation, GRID_EPSILON);
+        let axis_aligned = [Vec3::X, Vec3::Y, Vec3::Z].iter().all(|axis| {
+            let rotated = transform.rotation * *axis;
+            rotated.abs().max_element() > 1.0 - GRID_EPSILON
+        });
+        (on_grid && axis_aligned).then_some(placement)
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.origin.as_vec3() + (self.extent - IVec3::ONE).as_vec3() / 2.0) * GRID_CELL_SIZE
+    }
+
+    /// Transform relative to the ship.
+    pub fn transform(&self) -> Transform {
+        Transform::from_translation(self.center()).with_rotation(self.rotation)
+    }
+
+    pub fn contains(&self, cell: IVec3) -> bool {
+        cell.cmpge(self.origin).all() && cell.cmplt(self.origin + self.extent).all()
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = IVec3> + '_ {
+        (0..self.extent.x).flat_map(move |x| {
+            (0..self.extent.y).flat_map(move |y| {
+                (0..self.extent.z).map(move |z| self.origin + IVec3::new(x, y, z))
+            })
+        })
+    }
+}
+
+/// The cell containing a point given in ship-local space.
+pub fn cell_at(point: Vec3) -> IVec3 {
+    (point / GRID_CELL_SIZE).round().as_ivec3()
+}
+
+/// Maps every occupied cell of a ship to the module occupying it.
+pub fn occupied_cells<'a>(
+    placements: impl IntoIterator<Item = (Entity, &'a GridPlacement)>,
+) -> HashMap<IVec3, Entity> {
+    let mut cells = HashMap::new();
+    for (module, placement) in placements {
+        cells.extend(placement.cells().map(|cell| (cell, module)));
+    }
+    cells
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlacementError {
+    /// The target face, or the matching face of the new module, has no port
+    NoPort,
+    IncompatiblePort,
+    Occupied,
+}
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlacementError::NoPort => write!(f, "no port on that face"),
+            PlacementError::IncompatiblePort => write!(f, "ports are not compatible"),
+            PlacementError::Occupied => write!(f, "cells are already occupied"),
+        }
+    }
+}
+
+/// Where a module ends up when it is attached to a port, and the joint holding it there.
+#[derive(Clone, Copy, Debug)]
+pub struct Placement {
+    pub grid: GridPlacement,
+    pub joint: ModuleJoint,
+}
+
+/// The module a new module is being attached to.
+pub struct PlacementTarget<'a> {
+    pub module: Entity,
+    pub placement: &'a GridPlacement,
+    pub size: Vec3,
+    pub ports: &'a [PortDefinition],
+}
+
+/// Works out where a module from `definition`, rotated by `rotation`, has to go to be
+/// attached to `face` of the target module, given in the target's local space.
+pub fn plan_placement(
+    target: &PlacementTarget,
+    face: Face,
+    definition: &ModuleDefinition,
+    rotation: Quat,
+    occupied: &HashMap<IVec3, Entity>,
+) -> Result<Placement, PlacementError> {
+    let target_port = target
+        .ports
+        .iter()
+        .find(|port| port.face == face)
+        .ok_or(PlacementError::NoPort)?;
+
+    // The new module needs a port facing back at the target port
+    let outward = target.placement.rotation * face.normal();
+    let facing_ports: Vec<&PortDefinition> = definition
+        .ports
+        .iter()
+        .filter(|port| (rotation * port.face.normal()).dot(outward) < -1.0 + GRID_EPSILON)
+        .collect();
+    if facing_ports.is_empty() {
+        return Err(PlacementError::NoPort);
+    }
+    let port = facing_ports
+        .into_iter()
+        .find(|port| port.kind.is_compatible(target_port.kind))
+        .ok_or(PlacementError::IncompatiblePort)?;
+
+    let target_transform = target.placement.transform();
+    let port_position = target_transform.transform_point(target_port.local_position(target.size));
+    let anchor = port.local_position(definition.size);
+    let grid = GridPlacement::snapped(port_position - rotation * anchor, definition.size, rotation);
+
+    if grid.cells().any(|cell| occupied.contains_key(&cell)) {
+        return Err(PlacementError::Occupied);
+    }
+
+    Ok(Placement {
+        grid,
+        joint: ModuleJoint::new(target.module, anchor),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube_definition(ports: Vec<PortDefinition>) -> ModuleDefinition {
+        ModuleDefinition {
+            ports,
+            ..ron::from_str("(size: (1.0, 1.0, 1.0))").unwrap()
+        }
+    }
+
+    fn plan(
+        target_ports: &[PortDefinition],
+        definition: &ModuleDefinition,
+        occupied: &HashMap<IVec3, Entity>,
+    ) -> Result<Placement, PlacementError> {
+        let placement = GridPlacement::snapped(Vec3::ZERO, Vec3::ONE, Quat::IDENTITY);
+        let target = PlacementTarget {
+            module: Entity::from_raw(0),
+            placement: &placement,
+            size: Vec3::ONE,
+            ports: target_ports,
+        };
+        plan_placement(&target, Face::PosX, definition, Quat::IDENTITY, occupied)
+    }
+
+    #[test]
+    fn placement_next_to_free_port() {
+        let definition = cube_definition(default_ports());
+        let placement = plan(&default_ports(), &definition, &HashMap::new()).unwrap();
+        assert_eq!(placement.grid.origin, IVec3::X);
+        assert_eq!(placement.joint.anchor, Vec3::new(-0.5, 0.0, 0.0));
+    }
+
+    #[test]
+    fn placement_on_occupied_cell_fails() {
+        let definition = cube_definition(default_ports());
+        let occupied = HashMap::from_iter([(IVec3::X, Entity::from_raw(1))]);
+        assert_eq!(
+            plan(&default_ports(), &definition, &occupied).unwrap_err(),
+            PlacementError::Occupied
+        );
+    }
+
+    #[test]
+    fn placement_with_mismatched_port_kind_fails() {
+        let target_ports = [PortDefinition {
+            face: Face::PosX,
+            kind: PortKind::Small,
+        }];
+        let definition = cube_definition(default_ports());
+        assert_eq!(
+            plan(&target_ports, &definition, &HashMap::new()).unwrap_err(),
+            PlacementError::IncompatiblePort
+        );
+
+        let universal = cube_definition(vec![PortDefinition {
+            face: Face::NegX,
+            kind: PortKind::Universal,
+        }]);
+        assert!(plan(&target_ports, &universal, &HashMap::new()).is_ok());
+    }
+}
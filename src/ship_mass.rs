@@ -0,0 +1,152 @@
+use bevy::prelude::*;
+use bevy_rapier3d::{prelude::*, rapier};
+
+use crate::{
+    module::{module_inertia, module_mass, ModuleMass, ModuleShape, ModuleTag, Size},
+    ship::{ShipLayout, ShipTag},
+};
+
+/// Mass properties of a whole ship, worked out from its modules.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ShipMassProperties {
+    /// Total mass in kilograms
+    pub mass: f32,
+    /// Center of mass in the ship's local space
+    pub local_center_of_mass: Vec3,
+    /// Inertia tensor around the center of mass, in the ship's local space
+    pub inertia: Mat3,
+}
+
+impl ShipMassProperties {
+    /// Combines modules given as their transform relative to the ship, shape, size and mass.
+    pub fn from_modules<'a>(
+        modules: impl IntoIterator<Item = (Transform, &'a ModuleShape, &'a Size, f32)>,
+    ) -> Self {
+        let modules: Vec<_> = modules.into_iter().collect();
+
+        let mass: f32 = modules.iter().map(|(_, _, _, mass)| mass).sum();
+        if mass <= 0.0 {
+            return ShipMassProperties::default();
+        }
+        let local_center_of_mass = modules
+            .iter()
+            .map(|(transform, _, _, mass)| transform.translation * *mass)
+            .sum::<Vec3>()
+            / mass;
+
+        // Rotate every module's own inertia into the ship's space, then move it to the
+        // ship's center of mass with the parallel axis theorem
+        let mut inertia = Mat3::ZERO;
+        for (transform, shape, size, module_mass) in modules.iter() {
+            let rotation = Mat3::from_quat(transform.rotation);
+            let offset = transform.translation - local_center_of_mass;
+            let outer = Mat3::from_cols(offset * offset.x, offset * offset.y, offset * offset.z);
+            inertia +=
+                rotation * module_inertia(**shape, size.0, *module_mass) * rotation.transpose()
+                    + (Mat3::IDENTITY * offset.length_squared() - outer) * *module_mass;
+        }
+
+        ShipMassProperties {
+            mass,
+            local_center_of_mass,
+            inertia,
+        }
+    }
+
+    /// The same properties in the form Rapier expects.
+    pub fn to_mass_properties(self, physics_scale: f32) -> MassProperties {
+        MassProperties::from_rapier(
+            rapier::dynamics::MassProperties::with_inertia_matrix(
+                (self.local_center_of_mass / physics_scale).into(),
+                self.mass,
+                (self.inertia * (1.0 / (physics_scale * physics_scale))).into(),
+            ),
+            physics_scale,
+        )
+    }
+}
+
+/// Keeps the [`ShipMassProperties`] of every ship up to date. Merged ships also get them
+/// as their collider mass, so that the compound collider doesn't fall back to Rapier's
+/// default density.
+pub fn update_ship_mass_properties(
+    mut commands: Commands,
+    rapier_context: Res<RapierContext>,
+    ship_query: Query<
+        (
+            Entity,
+            Ref<ShipLayout>,
+            &GlobalTransform,
+            &Children,
+            Option<&ShipMassProperties>,
+        ),
+        With<ShipTag>,
+    >,
+    module_query: Query<
+        (
+            &Transform,
+            &GlobalTransform,
+            &ModuleShape,
+            &Size,
+            Option<&ModuleMass>,
+        ),
+        With<ModuleTag>,
+    >,
+) {
+    for (ship, layout, ship_global, children, current) in ship_query.iter() {
+        let mass_properties = ShipMassProperties::from_modules(
+            children
+                .iter()
+                .filter_map(|child| module_query.get(*child).ok())
+                .map(|(transform, global, shape, size, mass)| {
+                    // Jointed modules move around, so their local transform is outdated
+                    let transform = match *layout {
+                        ShipLayout::Merged => *transform,
+                        ShipLayout::Jointed => global.reparented_to(ship_global),
+                    };
+                    (transform, shape, size, module_mass(size, mass))
+                }),
+        );
+        if current == Some(&mass_properties) && !layout.is_changed() {
+            continue;
+        }
+
+        let mut ship_commands = commands.entity(ship);
+        ship_commands.insert(mass_properties);
+        if *layout == ShipLayout::Merged {
+            ship_commands.insert(ColliderMassProperties::MassProperties(
+                mass_properties.to_mass_properties(rapier_context.physics_scale()),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_masses_use_parallel_axis_inertia() {
+        let point = Size(Vec3::ZERO);
+        let mass_properties = ShipMassProperties::from_modules([
+            (Transform::IDENTITY, &ModuleShape::Cuboid, &point, 1.0),
+            (
+                Transform::from_xyz(4.0, 0.0, 0.0),
+                &ModuleShape::Cuboid,
+                &point,
+                3.0,
+            ),
+        ]);
+
+        assert_eq!(mass_properties.mass, 4.0);
+        assert_eq!(
+            mass_properties.local_center_of_mass,
+            Vec3::new(3.0, 0.0, 0.0)
+        );
+        // 1 kg at 3 m and 3 kg at 1 m from the center of mass, nothing around the x axis
+        assert_eq!(
+            mass_properties.inertia,
+            Mat3::from_diagonal(Vec3::new(0.0, 12.0, 12.0))
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use bevy::{prelude::*, utils::HashSet};
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    module::{module_mass, ModuleMass, ModuleTag, Size},
+    ship::{spawn_jointed_ship, spawn_ship, ModuleJoint, ShipLayout, ShipTag},
+    ship_graph::ShipGraph,
+};
+
+/// Sent when a ship falls apart into several pieces. The largest piece stays on the
+/// original ship, every other piece gets a ship of its own.
+#[derive(Event, Clone, Debug)]
+pub struct ShipSplitEvent {
+    pub original: Entity,
+    pub new_ships: Vec<Entity>,
+}
+
+/// Removes joints that point at despawned modules, and the `ImpulseJoint` of every
+/// module that lost its `ModuleJoint`.
+pub fn remove_stale_joints(
+    mut commands: Commands,
+    mut removed_modules: RemovedComponents<ModuleTag>,
+    mut removed_joints: RemovedComponents<ModuleJoint>,
+    joint_query: Query<(Entity, &ModuleJoint)>,
+    impulse_joint_query: Query<(), With<ImpulseJoint>>,
+) {
+    let removed_modules: HashSet<Entity> = removed_modules.read().collect();
+    if !removed_modules.is_empty() {
+        for (module, joint) in joint_query.iter() {
+            if removed_modules.contains(&joint.parent) {
+                commands.entity(module).remove::<ModuleJoint>();
+            }
+        }
+    }
+
+    for module in removed_joints.read() {
+        if impulse_joint_query.contains(module) {
+            commands.entity(module).remove::<ImpulseJoint>();
+        }
+    }
+}
+
+type SplitModuleComponents<'a> = (&'a GlobalTransform, &'a Size, Option<&'a ModuleMass>);
+
+fn piece_center_of_mass(
+    piece: &[Entity],
+    module_query: &Query<SplitModuleComponents, With<ModuleTag>>,
+) -> Vec3 {
+    let mut total_mass = 0.0;
+    let mut weighted_position = Vec3::ZERO;
+    for (global, size, mass) in piece.iter().filter_map(|m| module_query.get(*m).ok()) {
+        let mass = module_mass(size, mass);
+        total_mass += mass;
+        weighted_position += mass * global.translation();
+    }
+    if total_mass > 0.0 {
+        weighted_position / total_mass
+    } else {
+        weighted_position
+    }
+}
+
+pub fn split_disconnected_ships(
+    mut commands: Commands,
+    mut ship_graph: ResMut<ShipGraph>,
+    mut split_events: EventWriter<ShipSplitEvent>,
+    mut ship_query: Query<
+        (
+            &ShipLayout,
+            &GlobalTransform,
+            Option<&mut Velocity>,
+            Option<&ReadMassProperties>,
+        ),
+        With<ShipTag>,
+    >,
+    module_query: Query<SplitModuleComponents, With<ModuleTag>>,
+) {
+    for ship in ship_graph.take_dirty_ships() {
+        let mut pieces = ship_graph.connected_components(ship);
+        if pieces.len() <= 1 {
+            continue;
+        }
+        let Ok((layout, ship_global, velocity, mass_properties)) = ship_query.get_mut(ship) else {
+            continue;
+        };
+
+        // The largest piece stays on the original ship
+        pieces.sort_by_key(|piece| std::cmp::Reverse(piece.len()));
+        let ship_transform = ship_global.compute_transform();
+        let mut new_ships = Vec::new();
+
+        match layout {
+            ShipLayout::Merged => {
+                // Every piece keeps the velocity its center of mass had on the original body
+                let ship_velocity = velocity.as_deref().copied().unwrap_or_default();
+                let center_of_mass = ship_global.transform_point(
+                    mass_properties.map_or(Vec3::ZERO, |m| m.local_center_of_mass),
+                );
+                let piece_velocity = |piece: &[Entity]| {
+                    let offset = piece_center_of_mass(piece, &module_query) - center_of_mass;
+                    Velocity {
+                        linvel: ship_velocity.linvel + ship_velocity.angvel.cross(offset),
+                        angvel: ship_velocity.angvel,
+                    }
+                };
+
+                for piece in pieces.iter().skip(1) {
+                    let new_ship = spawn_ship(&mut commands, ship_transform, piece_velocity(piece));
+                    commands.entity(new_ship).push_children(piece);
+                    new_ships.push(new_ship);
+                }
+                if let Some(mut velocity) = velocity {
+                    *velocity = piece_velocity(&pieces[0]);
+                }
+            }
+            ShipLayout::Jointed => {
+                // Modules already have their own bodies and velocities
+                for piece in pieces.iter().skip(1) {
+                    let new_ship = spawn_jointed_ship(&mut commands, ship_transform);
+                    commands.entity(new_ship).push_children(piece);
+                    new_ships.push(new_ship);
+                }
+            }
+        }
+
+        split_events.send(ShipSplitEvent {
+            original: ship,
+            new_ships,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ship_graph_app, spawn_module};
+
+    #[test]
+    fn jointless_ship_is_not_split() {
+        let mut app = ship_graph_app(split_disconnected_ships);
+        app.add_event::<ShipSplitEvent>();
+
+        let ship = app
+            .world
+            .spawn((ShipTag, ShipLayout::Merged, TransformBundle::default()))
+            .id();
+        let modules: Vec<Entity> = (0..3)
+            .map(|index| {
+                spawn_module(
+                    &mut app.world,
+                    ship,
+                    (
+                        Size(Vec3::ONE),
+                        TransformBundle::from_transform(Transform::from_xyz(
+                            index as f32,
+                            0.0,
+                            0.0,
+                        )),
+                    ),
+                )
+            })
+            .collect();
+        app.update();
+
+        // Removing a module makes the ship check whether it is still connected
+        app.world.entity_mut(modules[2]).despawn_recursive();
+        app.update();
+
+        assert!(app.world.resource::<Events<ShipSplitEvent>>().is_empty());
+        for module in &modules[..2] {
+            assert_eq!(
+                app.world.get::<Parent>(*module).map(|parent| parent.get()),
+                Some(ship)
+            );
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::{collections::VecDeque, error::Error, fs, path::Path};
+
+use bevy::{
+    app::AppExit,
+    ecs::schedule::{ExecutorKind, ScheduleLabel},
+    prelude::*,
+    utils::{intern::Interned, HashSet},
+};
+use bevy_rapier3d::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
+
+use crate::{input::Action, ui_menu::UiVisibility};
+
+/// Rate of the fixed timestep used by the deterministic mode.
+pub const FIXED_TIMESTEP_HZ: f64 = 60.0;
+/// Where the input of a deterministic run is saved on exit, and replayed from.
+pub const INPUT_RECORDING_PATH: &str = "replays/input.ron";
+
+/// How the simulation advances. By default physics follows the frame time, in
+/// deterministic mode Rapier and the gameplay systems run on a fixed timestep instead, so
+/// two runs with the same inputs and seed produce identical module transforms.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimulationMode {
+    pub deterministic: bool,
+    /// Seed of the [`SimulationRng`], only used in deterministic mode
+    pub seed: u64,
+    /// Plays back the input saved at [`INPUT_RECORDING_PATH`] instead of the live input
+    pub replay: bool,
+}
+
+impl SimulationMode {
+    /// Reads `--deterministic`, `--seed <n>` and `--replay` from the command line arguments.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let mut mode = SimulationMode::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--deterministic" => mode.deterministic = true,
+                "--replay" => {
+                    mode.deterministic = true;
+                    mode.replay = true;
+                }
+                "--seed" => match args.next().map(|seed| seed.parse()) {
+                    Some(Ok(seed)) => mode.seed = seed,
+                    _ => println!("Expected a number after --seed"),
+                },
+                _ => {}
+            }
+        }
+        mode
+    }
+
+    /// Schedule the systems that change the simulation state run in.
+    pub fn gameplay_schedule(&self) -> Interned<dyn ScheduleLabel> {
+        match self.deterministic {
+            true => FixedUpdate.intern(),
+            false => Update.intern(),
+        }
+    }
+
+    /// Schedule Rapier steps in.
+    pub fn physics_schedule(&self) -> Interned<dyn ScheduleLabel> {
+        match self.deterministic {
+            true => FixedUpdate.intern(),
+            false => PostUpdate.intern(),
+        }
+    }
+
+    pub fn timestep_mode(&self) -> TimestepMode {
+        match self.deterministic {
+            true => TimestepMode::Fixed {
+                dt: (1.0 / FIXED_TIMESTEP_HZ) as f32,
+                substeps: 1,
+            },
+            false => RapierConfiguration::default().timestep_mode,
+        }
+    }
+}
+
+/// Random numbers for anything that affects the simulation. Only seeded in deterministic
+/// mode, so normal runs still vary.
+#[derive(Resource)]
+pub struct SimulationRng(pub fastrand::Rng);
+
+impl SimulationRng {
+    pub fn new(mode: &SimulationMode) -> Self {
+        match mode.deterministic {
+            true => SimulationRng(fastrand::Rng::with_seed(mode.seed)),
+            false => SimulationRng(fastrand::Rng::new()),
+        }
+    }
+}
+
+/// Systems that change the simulation state. In deterministic mode they run before Rapier
+/// in every fixed step.
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SimulationSet;
+
+/// Input of a single simulation step.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StepInput {
+    pub pressed: HashSet<Action>,
+    pub just_pressed: HashSet<Action>,
+    pub just_released: HashSet<Action>,
+    /// The camera aims the crosshair and tools, and expands ships near it
+    pub camera: Transform,
+    pub menu_open: bool,
+}
+
+/// Everything a deterministic run needs to be played back.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputRecording {
+    pub seed: u64,
+    pub steps: Vec<StepInput>,
+}
+
+impl InputRecording {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        if let Some(directory) = path.as_ref().parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(path, ron::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(ron::from_str(&text)?)
+    }
+}
+
+/// Player input as seen by the simulation. Presses and releases are latched every frame,
+/// and every simulation step takes what was latched since the step before as its
+/// [`StepInput`]. A step only ever reads its own input, so recording the input of every
+/// step is enough to play a deterministic run back.
+#[derive(Resource, Default)]
+pub struct SimulationInput {
+    step: StepInput,
+    latched: StepInput,
+    recording: Option<Vec<StepInput>>,
+    replay: VecDeque<StepInput>,
+}
+
+impl SimulationInput {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.step.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.step.just_pressed.contains(&action)
+    }
+
+    pub fn just_released(&self, action: Action) -> bool {
+        self.step.just_released.contains(&action)
+    }
+
+    pub fn camera(&self) -> &Transform {
+        &self.step.camera
+    }
+
+    pub fn menu_open(&self) -> bool {
+        self.step.menu_open
+    }
+
+    /// Moves the camera along with the world when the floating origin shifts.
+    pub fn shift_camera(&mut self, offset: Vec3) {
+        self.step.camera.translation += offset;
+        self.latched.camera.translation += offset;
+    }
+
+    /// Keeps the input of every step from now on.
+    pub fn start_recording(&mut self) {
+        self.recording.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_recording(&mut self) -> Option<Vec<StepInput>> {
+        self.recording.take()
+    }
+
+    /// Uses `steps` as the input of the next steps, before going back to the live input.
+    pub fn replay(&mut self, steps: impl IntoIterator<Item = StepInput>) {
+        self.replay.extend(steps);
+    }
+}
+
+/// Records the camera's input of this frame.
+pub fn latch_simulation_input(
+    mut input: ResMut<SimulationInput>,
+    ui_visibility: Res<UiVisibility>,
+    input_query: Query<(&ActionState<Action>, &Transform), With<Camera3d>>,
+) {
+    let Ok((action_state, camera)) = input_query.get_single() else {
+        return;
+    };
+    let latched = &mut input.latched;
+    latched.pressed = action_state.get_pressed().into_iter().collect();
+    latched.just_pressed.extend(action_state.get_just_pressed());
+    latched
+        .just_released
+        .extend(action_state.get_just_released());
+    latched.camera = *camera;
+    latched.menu_open = ui_visibility.any_open();
+}
+
+/// Hands the input latched since the last step to this step, or the next replayed step.
+pub fn advance_simulation_input(mut input: ResMut<SimulationInput>) {
+    let input = &mut *input;
+    let latched = StepInput {
+        pressed: input.latched.pressed.clone(),
+        just_pressed: std::mem::take(&mut input.latched.just_pressed),
+        just_released: std::mem::take(&mut input.latched.just_released),
+        camera: input.latched.camera,
+        menu_open: input.latched.menu_open,
+    };
+    input.step = input.replay.pop_front().unwrap_or(latched);
+    if let Some(recording) = &mut input.recording {
+        recording.push(input.step.clone());
+    }
+}
+
+/// Saves the recorded input when the app exits.
+pub fn save_input_recording(
+    mut exit_events: EventReader<AppExit>,
+    mode: Res<SimulationMode>,
+    mut input: ResMut<SimulationInput>,
+) {
+    if exit_events.read().count() == 0 {
+        return;
+    }
+    let Some(steps) = input.take_recording() else {
+        return;
+    };
+    let recording = InputRecording {
+        seed: mode.seed,
+        steps,
+    };
+    if let Err(e) = recording.save(INPUT_RECORDING_PATH) {
+        println!(
+            "Failed to save input recording {}: {}",
+            INPUT_RECORDING_PATH, e
+        );
+    }
+}
+
+/// Adds Rapier and the resources the simulation runs on. In deterministic mode the
+/// [`SimulationSet`] runs on a single thread before Rapier in every fixed step, so that
+/// gameplay systems see the same world in every step and add up forces in the same order.
+pub fn add_simulation(app: &mut App, mode: SimulationMode) {
+    app.insert_resource(mode)
+        .insert_resource(SimulationRng::new(&mode))
+        .insert_resource(Time::<Fixed>::from_hz(FIXED_TIMESTEP_HZ))
+        .insert_resource(RapierConfiguration {
+            timestep_mode: mode.timestep_mode(),
+            ..default()
+        })
+        .init_resource::<SimulationInput>()
+        .add_plugins(
+            RapierPhysicsPlugin::<NoUserData>::default().in_schedule(mode.physics_schedule()),
+        )
+        .add_systems(
+            mode.gameplay_schedule(),
+            advance_simulation_input.before(SimulationSet),
+        );
+
+    if mode.deterministic {
+        app.configure_sets(FixedUpdate, SimulationSet.before(PhysicsSet::SyncBackend))
+            .edit_schedule(FixedUpdate, |schedule| {
+                schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::{scene::SceneSpawner, time::TimeUpdateStrategy};
+
+    use super::*;
+    use crate::{
+        damage::{apply_damage, DamageEvent, DamageType, ModuleHealth},
+        module::{ModuleColor, ModuleTag, Size},
+    };
+
+    /// Drops a stack of modules on a floor and destroys one of them halfway through, then
+    /// returns the bits of every body's transform.
+    fn simulate(seed: u64, steps: usize) -> Vec<[u32; 7]> {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1.0 / FIXED_TIMESTEP_HZ,
+            )))
+            .insert_resource(Assets::<Mesh>::default())
+            .insert_resource(Assets::<StandardMaterial>::default())
+            .insert_resource(SceneSpawner::default())
+            .add_event::<DamageEvent>()
+            .add_systems(FixedUpdate, apply_damage.in_set(SimulationSet));
+        add_simulation(
+            &mut app,
+            SimulationMode {
+                deterministic: true,
+                seed,
+                replay: false,
+            },
+        );
+
+        app.world.spawn((
+            RigidBody::Fixed,
+            Collider::cuboid(10.0, 0.5, 10.0),
+            TransformBundle::from_transform(Transform::from_xyz(0.0, -1.0, 0.0)),
+        ));
+        let modules: Vec<Entity> = (0..4)
+            .map(|index| {
+                app.world
+                    .spawn((
+                        ModuleTag,
+                        Size(Vec3::ONE),
+                        ModuleColor(Color::GRAY),
+                        ModuleHealth::new(1.0),
+                        RigidBody::Dynamic,
+                        Collider::cuboid(0.5, 0.5, 0.5),
+                        ReadMassProperties::default(),
+                        Velocity::angular(Vec3::Y * index as f32),
+                        TransformBundle::from_transform(Transform::from_xyz(
+                            0.1 * index as f32,
+                            1.1 * index as f32,
+                            0.0,
+                        )),
+                    ))
+                    .id()
+            })
+            .collect();
+
+        for step in 0..steps {
+            if step == steps / 2 {
+                app.world.send_event(DamageEvent {
+                    target: modules[1],
+                    amount: 10.0,
+                    kind: DamageType::Kinetic,
+                    source: Some(modules[0]),
+                });
+            }
+            app.update();
+        }
+
+        let mut body_query = app
+            .world
+            .query_filtered::<(Entity, &Transform), With<RigidBody>>();
+        let mut bodies: Vec<(Entity, Transform)> = body_query
+            .iter(&app.world)
+            .map(|(entity, transform)| (entity, *transform))
+            .collect();
+        bodies.sort_by_key(|(entity, _)| *entity);
+        bodies
+            .iter()
+            .map(|(_, transform)| {
+                let [x, y, z] = transform.translation.to_array();
+                let [i, j, k, w] = transform.rotation.to_array();
+                [x, y, z, i, j, k, w].map(f32::to_bits)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_identical_transforms() {
+        let first = simulate(7, 120);
+        // The destroyed module left debris behind
+        assert!(first.len() > 5);
+        assert_eq!(first, simulate(7, 120));
+    }
+}
@@ -10,7 +10,7 @@ const CAMERA_MOVE_SPEED: f32 = 5.0;
 const CAMERA_BOOST_SPEED: f32 = 50.0;
 
 pub fn move_camera(
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     mut motion_evr: EventReader<MouseMotion>,
     mut query: Query<(&ActionState<Action>, &mut Transform), With<Camera3d>>,
     cursor_lock_state: Res<CursorLockState>,
@@ -84,6 +84,33 @@ impl SpectatorCameraBundle {
         input_map.insert(KeyCode::ControlLeft, Crouch);
         input_map.insert(KeyCode::ShiftLeft, Sprint);
 
+        //Building
+        input_map.insert(KeyCode::B, ToggleBuildMode);
+        input_map.insert(MouseButton::Left, PlaceModule);
+        input_map.insert(MouseButton::Right, RemoveModule);
+        input_map.insert(KeyCode::R, RotateModule);
+        input_map.insert(KeyCode::Tab, CycleModule);
+        input_map.insert(KeyCode::C, PaintModule);
+        input_map.insert(KeyCode::J, RejointModule);
+        input_map.insert(KeyCode::Z, Undo);
+        input_map.insert(KeyCode::Y, Redo);
+        input_map.insert(KeyCode::F5, SaveBlueprint);
+        input_map.insert(KeyCode::F9, LoadBlueprint);
+
+        //Ship controls
+        input_map.insert(KeyCode::Up, ThrottleUp);
+        input_map.insert(KeyCode::Down, ThrottleDown);
+        input_map.insert(KeyCode::X, ThrottleCut);
+        input_map.insert(KeyCode::F, FullThrottle);
+        input_map.insert(KeyCode::T, ToggleEngines);
+        input_map.insert(KeyCode::U, Undock);
+        input_map.insert(KeyCode::E, RotorForward);
+        input_map.insert(KeyCode::Q, RotorBackward);
+
+        //Simulation
+        input_map.insert(KeyCode::Period, WarpFaster);
+        input_map.insert(KeyCode::Comma, WarpSlower);
+
         //Return
         input_map
     }
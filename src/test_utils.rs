@@ -0,0 +1,21 @@
+use bevy::prelude::*;
+
+use crate::{
+    module::ModuleTag,
+    ship_graph::{sync_ship_graph, ShipGraph},
+};
+
+/// An app that syncs the [`ShipGraph`] right before running `systems` on every update.
+pub fn ship_graph_app<M>(systems: impl IntoSystemConfigs<M>) -> App {
+    let mut app = App::new();
+    app.insert_resource(ShipGraph::default())
+        .add_systems(Update, (sync_ship_graph, systems).chain());
+    app
+}
+
+/// Spawns a module made of `bundle` as a child of `ship`.
+pub fn spawn_module(world: &mut World, ship: Entity, bundle: impl Bundle) -> Entity {
+    let module = world.spawn((ModuleTag, bundle)).id();
+    world.entity_mut(ship).push_children(&[module]);
+    module
+}
@@ -0,0 +1,344 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    module::ModuleEngineTag, ship::ShipTag, ship_controls::Throttle, ship_mass::ShipMassProperties,
+};
+
+/// Number of sweeps over all engines when solving for throttles.
+const SOLVER_ITERATIONS: usize = 64;
+/// Stops solving once no throttle changes by more than this in a sweep.
+const SOLVER_TOLERANCE: f32 = 1e-5;
+/// Accelerations below this are treated as not achievable.
+const ACTUATION_EPSILON: f32 = 1e-6;
+/// How far the achieved acceleration may miss the requested one before the axis counts as
+/// under-actuated.
+const UNDER_ACTUATION_TOLERANCE: f32 = 1e-3;
+/// How strongly the flight assist counters the spin of a ship, per second.
+const SPIN_DAMPING: f32 = 1.0;
+
+/// Fraction of an engine's thrust that it is currently firing with, between 0 and 1.
+/// Engines without it fire at full thrust.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct EngineThrottle(pub f32);
+
+/// Accelerations the pilot wants from a ship, in the ship's local space.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrustRequest {
+    pub linear: Vec3,
+    pub angular: Vec3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlAxis {
+    LinearX,
+    LinearY,
+    LinearZ,
+    AngularX,
+    AngularY,
+    AngularZ,
+}
+
+impl ControlAxis {
+    pub const ALL: [ControlAxis; 6] = [
+        ControlAxis::LinearX,
+        ControlAxis::LinearY,
+        ControlAxis::LinearZ,
+        ControlAxis::AngularX,
+        ControlAxis::AngularY,
+        ControlAxis::AngularZ,
+    ];
+}
+
+/// A direction along a control axis that the engines couldn't accelerate in as much as
+/// requested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnderActuatedAxis {
+    pub axis: ControlAxis,
+    pub positive: bool,
+}
+
+/// An engine, relative to the center of mass of the body it pushes.
+#[derive(Clone, Copy, Debug)]
+pub struct Thruster {
+    pub position: Vec3,
+    /// Direction the engine pushes the body in
+    pub direction: Vec3,
+    pub thrust: f32,
+}
+
+/// The mass properties of the body the thrusters push, in the same space as the thrusters.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationBody {
+    pub mass: f32,
+    pub inverse_inertia: Mat3,
+}
+
+impl AllocationBody {
+    pub fn from_ship_mass_properties(mass_properties: &ShipMassProperties) -> Self {
+        let inverse_inertia = match mass_properties.inertia.determinant() > 0.0 {
+            true => mass_properties.inertia.inverse(),
+            false => Mat3::ZERO,
+        };
+        AllocationBody {
+            mass: mass_properties.mass,
+            inverse_inertia,
+        }
+    }
+}
+
+/// The throttles picked for a [`ThrustRequest`], and what they achieve.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct ThrustAllocation {
+    pub throttles: Vec<f32>,
+    pub linear: Vec3,
+    pub angular: Vec3,
+    pub under_actuated: Vec<UnderActuatedAxis>,
+}
+
+type Wrench = [f32; 6];
+
+fn wrench(linear: Vec3, angular: Vec3) -> Wrench {
+    [
+        linear.x, linear.y, linear.z, angular.x, angular.y, angular.z,
+    ]
+}
+
+fn dot(a: &Wrench, b: &Wrench) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+/// Accelerations of the body when `thruster` fires at full thrust.
+fn thruster_acceleration(thruster: &Thruster, body: &AllocationBody) -> Wrench {
+    let force = thruster.direction.normalize_or_zero() * thruster.thrust;
+    let linear = if body.mass > 0.0 {
+        force / body.mass
+    } else {
+        Vec3::ZERO
+    };
+    let angular = body.inverse_inertia * thruster.position.cross(force);
+    wrench(linear, angular)
+}
+
+/// Picks a throttle between 0 and 1 for every thruster, so that the resulting accelerations
+/// are as close as possible to the requested ones in the least squares sense. Solved with
+/// projected coordinate descent, which converges because the problem is convex.
+pub fn allocate_thrusters(
+    thrusters: &[Thruster],
+    body: &AllocationBody,
+    linear: Vec3,
+    angular: Vec3,
+) -> ThrustAllocation {
+    let columns: Vec<Wrench> = thrusters
+        .iter()
+        .map(|thruster| thruster_acceleration(thruster, body))
+        .collect();
+    let target = wrench(linear, angular);
+
+    let mut throttles = vec![0.0; thrusters.len()];
+    // Achieved minus requested acceleration
+    let mut residual = target.map(|value| -value);
+
+    for _ in 0..SOLVER_ITERATIONS {
+        let mut max_change: f32 = 0.0;
+        for (throttle, column) in throttles.iter_mut().zip(columns.iter()) {
+            let norm = dot(column, column);
+            if norm <= ACTUATION_EPSILON * ACTUATION_EPSILON {
+                continue;
+            }
+            let new_throttle = (*throttle - dot(column, &residual) / norm).clamp(0.0, 1.0);
+            let change = new_throttle - *throttle;
+            if change != 0.0 {
+                for (r, c) in residual.iter_mut().zip(column.iter()) {
+                    *r += c * change;
+                }
+                *throttle = new_throttle;
+                max_change = max_change.max(change.abs());
+            }
+        }
+        if max_change < SOLVER_TOLERANCE {
+            break;
+        }
+    }
+
+    let achieved: Vec<f32> = target
+        .iter()
+        .zip(residual.iter())
+        .map(|(t, r)| t + r)
+        .collect();
+
+    // The residual points away from the direction the body still needed to go
+    let under_actuated = ControlAxis::ALL
+        .iter()
+        .zip(residual.iter())
+        .filter(|(_, residual)| residual.abs() > UNDER_ACTUATION_TOLERANCE)
+        .map(|(axis, residual)| UnderActuatedAxis {
+            axis: *axis,
+            positive: *residual < 0.0,
+        })
+        .collect();
+
+    ThrustAllocation {
+        throttles,
+        linear: Vec3::new(achieved[0], achieved[1], achieved[2]),
+        angular: Vec3::new(achieved[3], achieved[4], achieved[5]),
+        under_actuated,
+    }
+}
+
+/// Turns the throttle of every ship into a request to accelerate along the combined
+/// direction of its engines, while countering its spin. Allocating it throttles down engines
+/// that are off the center of mass, and the ship throttle scales the result.
+pub fn update_thrust_requests(
+    mut commands: Commands,
+    ship_query: Query<
+        (
+            Entity,
+            &GlobalTransform,
+            &ShipMassProperties,
+            &Children,
+            Option<&Velocity>,
+        ),
+        (With<ShipTag>, With<Throttle>),
+    >,
+    engine_query: Query<(&ModuleEngineTag, &GlobalTransform)>,
+) {
+    for (ship, ship_global, mass_properties, children, velocity) in ship_query.iter() {
+        let (_, ship_rotation, _) = ship_global.to_scale_rotation_translation();
+        let force: Vec3 = children
+            .iter()
+            .filter_map(|child| engine_query.get(*child).ok())
+            .map(|(engine, global)| {
+                global.reparented_to(ship_global).rotation * Vec3::Y * engine.thrust
+            })
+            .sum();
+        let linear = match mass_properties.mass > 0.0 {
+            true => force / mass_properties.mass,
+            false => Vec3::ZERO,
+        };
+        // Jointed ships have no velocity of their own
+        let angular = -ship_rotation.inverse()
+            * velocity.map_or(Vec3::ZERO, |velocity| velocity.angvel)
+            * SPIN_DAMPING;
+
+        commands
+            .entity(ship)
+            .insert(ThrustRequest { linear, angular });
+    }
+}
+
+/// Sets the throttle of every engine on ships that have a [`ThrustRequest`].
+pub fn allocate_ship_thrust(
+    mut commands: Commands,
+    ship_query: Query<
+        (
+            Entity,
+            &ThrustRequest,
+            &GlobalTransform,
+            &ShipMassProperties,
+            &Children,
+        ),
+        With<ShipTag>,
+    >,
+    mut engine_query: Query<(
+        &ModuleEngineTag,
+        &GlobalTransform,
+        Option<&mut EngineThrottle>,
+    )>,
+) {
+    for (ship, request, ship_global, mass_properties, children) in ship_query.iter() {
+        let engines: Vec<Entity> = children
+            .iter()
+            .copied()
+            .filter(|child| engine_query.contains(*child))
+            .collect();
+        let thrusters: Vec<Thruster> = engines
+            .iter()
+            .filter_map(|engine| engine_query.get(*engine).ok())
+            .map(|(engine, global, _)| {
+                let local = global.reparented_to(ship_global);
+                Thruster {
+                    position: local.translation - mass_properties.local_center_of_mass,
+                    direction: local.rotation * Vec3::Y,
+                    thrust: engine.thrust,
+                }
+            })
+            .collect();
+
+        let allocation = allocate_thrusters(
+            &thrusters,
+            &AllocationBody::from_ship_mass_properties(mass_properties),
+            request.linear,
+            request.angular,
+        );
+
+        for (engine, throttle) in engines.iter().zip(allocation.throttles.iter()) {
+            match engine_query.get_mut(*engine) {
+                Ok((_, _, Some(mut engine_throttle))) => engine_throttle.0 = *throttle,
+                Ok((_, _, None)) => {
+                    commands.entity(*engine).insert(EngineThrottle(*throttle));
+                }
+                Err(_) => {}
+            }
+        }
+        commands.entity(ship).insert(allocation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: AllocationBody = AllocationBody {
+        mass: 10.0,
+        inverse_inertia: Mat3::from_diagonal(Vec3::splat(0.1)),
+    };
+
+    fn thruster(position: Vec3) -> Thruster {
+        Thruster {
+            position,
+            direction: Vec3::Y,
+            thrust: 100.0,
+        }
+    }
+
+    #[test]
+    fn symmetric_engines_share_the_load() {
+        let thrusters = [thruster(Vec3::X), thruster(Vec3::NEG_X)];
+        let allocation = allocate_thrusters(&thrusters, &BODY, Vec3::Y * 10.0, Vec3::ZERO);
+        assert_eq!(allocation.throttles, vec![0.5, 0.5]);
+        assert!(allocation.linear.abs_diff_eq(Vec3::Y * 10.0, 1e-4));
+        assert!(allocation.angular.abs_diff_eq(Vec3::ZERO, 1e-4));
+        assert!(allocation.under_actuated.is_empty());
+    }
+
+    #[test]
+    fn off_center_engine_is_under_actuated() {
+        let allocation =
+            allocate_thrusters(&[thruster(Vec3::X)], &BODY, Vec3::Y * 10.0, Vec3::ZERO);
+        assert!(allocation.throttles[0] > 0.0 && allocation.throttles[0] < 1.0);
+        // Falls short of the requested acceleration, and spins the ship around z
+        assert!(allocation.under_actuated.contains(&UnderActuatedAxis {
+            axis: ControlAxis::LinearY,
+            positive: true,
+        }));
+        assert!(allocation.under_actuated.contains(&UnderActuatedAxis {
+            axis: ControlAxis::AngularZ,
+            positive: false,
+        }));
+    }
+
+    #[test]
+    fn missing_direction_is_under_actuated() {
+        let thrusters = [thruster(Vec3::X), thruster(Vec3::NEG_X)];
+        let allocation = allocate_thrusters(&thrusters, &BODY, Vec3::NEG_Y * 5.0, Vec3::ZERO);
+        assert_eq!(allocation.throttles, vec![0.0, 0.0]);
+        assert_eq!(
+            allocation.under_actuated,
+            vec![UnderActuatedAxis {
+                axis: ControlAxis::LinearY,
+                positive: false,
+            }]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Align2},
+    EguiContexts,
+};
+use bevy_rapier3d::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::{input::Action, ui_menu::UiVisibility};
+
+/// Multipliers the simulation rate can be set to.
+const WARP_LEVELS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 10.0];
+const DEFAULT_WARP_LEVEL: usize = 2;
+/// Most physics steps a single frame may run, the simulation falls behind instead.
+const MAX_STEPS_PER_FRAME: u32 = 10;
+/// Highest multiplier allowed while bodies touch, larger steps make contacts and the
+/// joints around them explode.
+const MAX_CONTACT_WARP: f32 = 1.0;
+
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWarp {
+    /// Index into the warp levels
+    pub level: usize,
+    /// Set when the warp was lowered because bodies are in contact
+    pub limited: bool,
+    /// Set when warping faster was refused because bodies are in contact
+    pub refused: bool,
+}
+
+impl Default for TimeWarp {
+    fn default() -> Self {
+        TimeWarp {
+            level: DEFAULT_WARP_LEVEL,
+            limited: false,
+            refused: false,
+        }
+    }
+}
+
+impl TimeWarp {
+    pub fn multiplier(&self) -> f32 {
+        WARP_LEVELS[self.level]
+    }
+}
+
+/// Whether any two colliders are touching.
+fn bodies_in_contact(rapier_context: &RapierContext) -> bool {
+    rapier_context
+        .contact_pairs()
+        .any(|pair| pair.has_any_active_contacts())
+}
+
+/// Changes the warp level on input and lowers it while bodies are in contact.
+pub fn control_time_warp(
+    input_query: Query<&ActionState<Action>, With<Camera3d>>,
+    rapier_context: Res<RapierContext>,
+    ui_visibility: Res<UiVisibility>,
+    mut time_warp: ResMut<TimeWarp>,
+) {
+    if ui_visibility.any_open() {
+        return;
+    }
+    let Ok(action_state) = input_query.get_single() else {
+        return;
+    };
+    let in_contact = bodies_in_contact(&rapier_context);
+
+    // The notice only answers the last warp input
+    if action_state.just_pressed(Action::WarpFaster)
+        || action_state.just_pressed(Action::WarpSlower)
+    {
+        time_warp.refused = false;
+    }
+    if action_state.just_pressed(Action::WarpFaster) && time_warp.level + 1 < WARP_LEVELS.len() {
+        if in_contact && WARP_LEVELS[time_warp.level + 1] > MAX_CONTACT_WARP {
+            time_warp.refused = true;
+        } else {
+            time_warp.level += 1;
+        }
+    }
+    if action_state.just_pressed(Action::WarpSlower) && time_warp.level > 0 {
+        time_warp.level -= 1;
+    }
+
+    if in_contact && time_warp.multiplier() > MAX_CONTACT_WARP {
+        time_warp.level = WARP_LEVELS
+            .iter()
+            .rposition(|warp| *warp <= MAX_CONTACT_WARP)
+            .unwrap_or(0);
+        time_warp.limited = true;
+    } else if time_warp.limited && !in_contact {
+        time_warp.limited = false;
+    }
+    if time_warp.refused && !in_contact {
+        time_warp.refused = false;
+    }
+}
+
+/// Speeds up virtual time by the warp multiplier. Fixed timestep systems run more steps per
+/// frame, the variable timestep gets split into substeps, both capped at
+/// [`MAX_STEPS_PER_FRAME`].
+pub fn apply_time_warp(
+    time_warp: Res<TimeWarp>,
+    fixed_time: Res<Time<Fixed>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut rapier_config: ResMut<RapierConfiguration>,
+) {
+    if !time_warp.is_changed() {
+        return;
+    }
+    let warp = time_warp.multiplier();
+    let step = fixed_time.timestep().as_secs_f32();
+
+    virtual_time.set_relative_speed(warp);
+    // The clamp applies before the speed, so it has to shrink as the warp grows
+    virtual_time.set_max_delta(Duration::from_secs_f32(
+        step * MAX_STEPS_PER_FRAME as f32 / warp,
+    ));
+
+    if let TimestepMode::Variable { .. } = rapier_config.timestep_mode {
+        let substeps = (warp.ceil() as usize).clamp(1, MAX_STEPS_PER_FRAME as usize);
+        rapier_config.timestep_mode = TimestepMode::Variable {
+            max_dt: step * substeps as f32,
+            time_scale: 1.0,
+            substeps,
+        };
+    }
+}
+
+pub fn show_time_warp(mut contexts: EguiContexts, time_warp: Res<TimeWarp>) {
+    egui::Area::new("Time Warp")
+        .anchor(Align2::RIGHT_TOP, [-10.0, 10.0])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(match (time_warp.limited, time_warp.refused) {
+                (true, _) => format!("Time Warp {}x (bodies in contact)", time_warp.multiplier()),
+                (false, true) => format!(
+                    "Time Warp {}x (can't warp faster while bodies are in contact)",
+                    time_warp.multiplier()
+                ),
+                (false, false) => format!("Time Warp {}x", time_warp.multiplier()),
+            });
+        });
+}
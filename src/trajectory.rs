@@ -0,0 +1,413 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    floating_origin::OriginShiftEvent,
+    gravity::{gravity_acceleration, GravitySource},
+    module::{module_mass, ModuleMass, ModuleTag, Size},
+    ship::ShipTag,
+    ship_mass::ShipMassProperties,
+    ship_split::ShipSplitEvent,
+};
+
+const TRAJECTORY_COLOR: Color = Color::CYAN;
+const IMPACT_COLOR: Color = Color::RED;
+const CLOSEST_APPROACH_COLOR: Color = Color::YELLOW;
+/// Radius of the markers drawn at impact and closest approach points.
+const MARKER_RADIUS: f32 = 1.0;
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct TrajectorySettings {
+    /// How far ahead paths are predicted, in seconds
+    pub horizon: f32,
+    /// Time step of the prediction in seconds, smaller steps are more precise but slower
+    pub step: f32,
+    /// Seconds after which a path is predicted again, even if the ship kept its velocity
+    pub refresh_interval: f32,
+    /// Change of velocity in m/s that makes a path be predicted again right away
+    pub velocity_tolerance: f32,
+}
+
+impl Default for TrajectorySettings {
+    fn default() -> Self {
+        TrajectorySettings {
+            horizon: 600.0,
+            step: 0.5,
+            refresh_interval: 1.0,
+            velocity_tolerance: 0.1,
+        }
+    }
+}
+
+/// Where a predicted path gets closest to a gravity source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClosestApproach {
+    pub source: Entity,
+    /// Distance to the center of the source in meters
+    pub distance: f32,
+    /// Seconds from now
+    pub time: f32,
+    pub point: Vec3,
+}
+
+/// Where a predicted path hits the surface of a gravity source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Impact {
+    pub source: Entity,
+    /// Seconds from now
+    pub time: f32,
+    pub point: Vec3,
+}
+
+/// The predicted path of a ship's center of mass.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct Trajectory {
+    pub points: Vec<Vec3>,
+    pub closest_approaches: Vec<ClosestApproach>,
+    pub impact: Option<Impact>,
+    /// Velocity the path was predicted with
+    pub start_velocity: Vec3,
+    /// Seconds since the path was predicted
+    pub age: f32,
+}
+
+/// A gravity source as seen by the prediction.
+#[derive(Clone, Copy, Debug)]
+pub struct PredictedSource {
+    pub entity: Entity,
+    pub source: GravitySource,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    /// Sources without a dynamic body stay where they are
+    pub movable: bool,
+}
+
+/// Fraction of the way from `start` to `end` at which the segment first touches a sphere of
+/// `radius` around the origin.
+fn segment_sphere_entry(start: Vec3, end: Vec3, radius: f32) -> Option<f32> {
+    if start.length_squared() <= radius * radius {
+        return Some(0.0);
+    }
+    let direction = end - start;
+    let a = direction.length_squared();
+    let b = 2.0 * start.dot(direction);
+    let c = start.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if a <= 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    (0.0..=1.0).contains(&t).then_some(t)
+}
+
+/// Predicts the path of a point mass starting at `position` with `velocity`. Movable sources
+/// are moved under each other's pull as well, using the same gravity model as the live
+/// simulation, but none of it touches the Rapier world.
+pub fn predict_trajectory(
+    mut position: Vec3,
+    mut velocity: Vec3,
+    mut sources: Vec<PredictedSource>,
+    settings: &TrajectorySettings,
+) -> Trajectory {
+    let mut trajectory = Trajectory {
+        points: vec![position],
+        closest_approaches: sources
+            .iter()
+            .map(|source| ClosestApproach {
+                source: source.entity,
+                distance: position.distance(source.position),
+                time: 0.0,
+                point: position,
+            })
+            .collect(),
+        impact: None,
+        start_velocity: velocity,
+        age: 0.0,
+    };
+    if settings.step <= 0.0 {
+        return trajectory;
+    }
+
+    let steps = (settings.horizon / settings.step).ceil() as usize;
+    let dt = settings.step;
+    trajectory.points.reserve(steps);
+    let mut source_accelerations = vec![Vec3::ZERO; sources.len()];
+    let mut previous_sources = vec![Vec3::ZERO; sources.len()];
+    for step in 1..=steps {
+        let time = step as f32 * dt;
+        let previous_position = position;
+
+        // Semi-implicit Euler, like Rapier
+        for (index, acceleration) in source_accelerations.iter_mut().enumerate() {
+            *acceleration = match sources[index].movable {
+                true => gravity_acceleration(
+                    sources[index].position,
+                    sources
+                        .iter()
+                        .enumerate()
+                        .filter(|(other, _)| *other != index)
+                        .map(|(_, other)| (other.position, &other.source)),
+                ),
+                false => Vec3::ZERO,
+            };
+        }
+        velocity += gravity_acceleration(
+            position,
+            sources
+                .iter()
+                .map(|source| (source.position, &source.source)),
+        ) * dt;
+        position += velocity * dt;
+        for ((source, acceleration), previous) in sources
+            .iter_mut()
+            .zip(source_accelerations.iter())
+            .zip(previous_sources.iter_mut())
+        {
+            *previous = source.position;
+            source.velocity += *acceleration * dt;
+            source.position += source.velocity * dt;
+        }
+
+        for (source, approach) in sources.iter().zip(trajectory.closest_approaches.iter_mut()) {
+            let distance = position.distance(source.position);
+            if distance < approach.distance {
+                *approach = ClosestApproach {
+                    source: source.entity,
+                    distance,
+                    time,
+                    point: position,
+                };
+            }
+        }
+
+        // Checking the whole step, relative to each source, keeps fast paths from skipping
+        // over a surface between two samples
+        for (source, previous) in sources.iter().zip(previous_sources.iter()) {
+            let Some(radius) = source.source.radius else {
+                continue;
+            };
+            let start = previous_position - *previous;
+            let end = position - source.position;
+            let Some(t) = segment_sphere_entry(start, end, radius) else {
+                continue;
+            };
+            let impact_time = time - dt + t * dt;
+            if trajectory
+                .impact
+                .is_none_or(|impact| impact_time < impact.time)
+            {
+                trajectory.impact = Some(Impact {
+                    source: source.entity,
+                    time: impact_time,
+                    point: previous.lerp(source.position, t) + start.lerp(end, t),
+                });
+            }
+        }
+        if let Some(impact) = &trajectory.impact {
+            trajectory.points.push(impact.point);
+            break;
+        }
+        trajectory.points.push(position);
+    }
+    trajectory
+}
+
+/// Predicts the path of every ship that is pulled by a gravity source. Paths are only
+/// predicted again once they are old or the ship's velocity changed.
+pub fn predict_trajectories(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<TrajectorySettings>,
+    source_query: Query<(
+        Entity,
+        &GravitySource,
+        &GlobalTransform,
+        Option<&RigidBody>,
+        Option<&Velocity>,
+    )>,
+    mut ship_query: Query<
+        (
+            Entity,
+            &GlobalTransform,
+            &ShipMassProperties,
+            Option<&Velocity>,
+            &Children,
+            Option<&mut Trajectory>,
+        ),
+        With<ShipTag>,
+    >,
+    module_query: Query<(&Size, Option<&ModuleMass>, &Velocity), With<ModuleTag>>,
+) {
+    for (ship, global, mass_properties, velocity, children, trajectory) in ship_query.iter_mut() {
+        let position = global.transform_point(mass_properties.local_center_of_mass);
+        let sources: Vec<PredictedSource> = source_query
+            .iter()
+            .filter(|(entity, ..)| *entity != ship)
+            .map(
+                |(entity, source, source_global, body, velocity)| PredictedSource {
+                    entity,
+                    source: *source,
+                    position: source_global.translation(),
+                    velocity: velocity.map_or(Vec3::ZERO, |v| v.linvel),
+                    movable: body == Some(&RigidBody::Dynamic),
+                },
+            )
+            .collect();
+
+        let pulled = gravity_acceleration(
+            position,
+            sources
+                .iter()
+                .map(|source| (source.position, &source.source)),
+        ) != Vec3::ZERO;
+        if !pulled {
+            if trajectory.is_some() {
+                commands.entity(ship).remove::<Trajectory>();
+            }
+            continue;
+        }
+
+        // Jointed ships move with the average velocity of their modules
+        let velocity = match velocity {
+            Some(velocity) => velocity.linvel,
+            None => {
+                let (mass, momentum) = children
+                    .iter()
+                    .filter_map(|child| module_query.get(*child).ok())
+                    .fold((0.0, Vec3::ZERO), |(mass, momentum), (size, m, v)| {
+                        let m = module_mass(size, m);
+                        (mass + m, momentum + v.linvel * m)
+                    });
+                if mass > 0.0 {
+                    momentum / mass
+                } else {
+                    Vec3::ZERO
+                }
+            }
+        };
+
+        if let Some(mut trajectory) = trajectory {
+            trajectory.age += time.delta_seconds();
+            let velocity_changed =
+                trajectory.start_velocity.distance(velocity) > settings.velocity_tolerance;
+            if trajectory.age < settings.refresh_interval && !velocity_changed {
+                continue;
+            }
+        }
+
+        commands
+            .entity(ship)
+            .insert(predict_trajectory(position, velocity, sources, &settings));
+    }
+}
+
+/// Pieces of a ship that split apart have a different mass and velocity, so their paths
+/// are predicted again right away.
+pub fn refresh_split_trajectories(
+    mut commands: Commands,
+    mut split_events: EventReader<ShipSplitEvent>,
+) {
+    for event in split_events.read() {
+        for ship in std::iter::once(event.original).chain(event.new_ships.iter().copied()) {
+            if let Some(mut ship_commands) = commands.get_entity(ship) {
+                ship_commands.remove::<Trajectory>();
+            }
+        }
+    }
+}
+
+/// Keeps predicted paths in place when the floating origin moves the world.
+pub fn shift_trajectories(
+    mut shift_events: EventReader<OriginShiftEvent>,
+    mut trajectory_query: Query<&mut Trajectory>,
+) {
+    for event in shift_events.read() {
+        for mut trajectory in trajectory_query.iter_mut() {
+            for point in trajectory.points.iter_mut() {
+                *point += event.offset;
+            }
+            for approach in trajectory.closest_approaches.iter_mut() {
+                approach.point += event.offset;
+            }
+            if let Some(impact) = &mut trajectory.impact {
+                impact.point += event.offset;
+            }
+        }
+    }
+}
+
+pub fn draw_trajectories(mut gizmos: Gizmos, trajectory_query: Query<&Trajectory>) {
+    for trajectory in trajectory_query.iter() {
+        gizmos.linestrip(trajectory.points.iter().copied(), TRAJECTORY_COLOR);
+        for approach in trajectory.closest_approaches.iter() {
+            gizmos.sphere(
+                approach.point,
+                Quat::IDENTITY,
+                MARKER_RADIUS,
+                CLOSEST_APPROACH_COLOR,
+            );
+        }
+        if let Some(impact) = &trajectory.impact {
+            gizmos.sphere(impact.point, Quat::IDENTITY, MARKER_RADIUS, IMPACT_COLOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gravity::GRAVITATIONAL_CONSTANT;
+
+    /// Gravitational parameter of the test source, in m³/s².
+    const MU: f32 = 1000.0;
+
+    fn source(radius: f32) -> PredictedSource {
+        PredictedSource {
+            entity: Entity::from_raw(0),
+            source: GravitySource {
+                mass: MU / GRAVITATIONAL_CONSTANT,
+                influence_radius: None,
+                radius: Some(radius),
+            },
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            movable: false,
+        }
+    }
+
+    #[test]
+    fn circular_orbit_stays_bound() {
+        let radius = 100.0;
+        let speed = (MU / radius).sqrt();
+        let trajectory = predict_trajectory(
+            Vec3::X * radius,
+            Vec3::Y * speed,
+            vec![source(1.0)],
+            &TrajectorySettings::default(),
+        );
+
+        assert!(trajectory.impact.is_none());
+        // Several full orbits, none of which drift away from the starting radius
+        assert!(trajectory.points.len() > 1000);
+        for point in trajectory.points.iter() {
+            assert!((point.length() - radius).abs() < radius * 0.05);
+        }
+    }
+
+    #[test]
+    fn straight_in_path_hits_the_surface() {
+        // Fast enough to step over the whole source between two samples
+        let trajectory = predict_trajectory(
+            Vec3::X * 100.0,
+            Vec3::NEG_X * 20.0,
+            vec![source(2.0)],
+            &TrajectorySettings::default(),
+        );
+
+        let impact = trajectory.impact.unwrap();
+        assert_eq!(impact.source, Entity::from_raw(0));
+        assert!(impact.time > 0.0 && impact.time < 5.0);
+        assert!(impact.point.abs_diff_eq(Vec3::X * 2.0, 1e-2));
+        assert_eq!(trajectory.points.last(), Some(&impact.point));
+    }
+}
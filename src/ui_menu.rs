@@ -13,8 +13,9 @@ use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter};
 
 use crate::{
-    input::Action, menu_focus::CursorLockState, settings::*, settings_io::*,
-    spectator_camera::update_fov,
+    crosshair::CrosshairTarget, floating_origin::FloatingOrigin, input::Action,
+    menu_focus::CursorLockState, power::ShipPower, settings::*, settings_io::*,
+    ship_mass::ShipMassProperties, spectator_camera::update_fov, trajectory::Trajectory,
 };
 
 const SETTINGS_BUTTON_HEIGHT: f32 = 18.0;
@@ -26,6 +27,12 @@ pub struct UiVisibility {
     pub settings_tab_option: SettingsTabOption,
 }
 
+impl UiVisibility {
+    pub fn any_open(&self) -> bool {
+        self.escape_menu || self.settings_menu
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, EnumIter, Display, Default)]
 pub enum SettingsTabOption {
     #[default]
@@ -36,6 +43,10 @@ pub enum SettingsTabOption {
     Debug,
 }
 
+fn format_vec3(vector: Vec3) -> String {
+    format!("{:.2}, {:.2}, {:.2}", vector.x, vector.y, vector.z)
+}
+
 pub fn set_cursor_lock(window: &mut Window, cursor_lock_state: Res<CursorLockState>) {
     if cursor_lock_state.0 {
         window.cursor.visible = false;
@@ -57,6 +68,11 @@ pub fn ui_menu(
     mut control_settings: ResMut<ControlSettings>,
     mut graphics_settings: ResMut<GraphicsSettings>,
     mut pkv: ResMut<PkvStore>,
+    crosshair_target: Res<CrosshairTarget>,
+    origin: Res<FloatingOrigin>,
+    ship_mass_query: Query<&ShipMassProperties>,
+    ship_power_query: Query<&ShipPower>,
+    trajectory_query: Query<&Trajectory>,
 ) {
     let action_state = input_query.single_mut();
     let mut window = windows.single_mut();
@@ -74,7 +90,7 @@ pub fn ui_menu(
             ui_visibility.escape_menu = !ui_visibility.escape_menu;
         }
 
-        let ui_window_open = ui_visibility.escape_menu || ui_visibility.settings_menu;
+        let ui_window_open = ui_visibility.any_open();
         cursor_lock_state.0 = !ui_window_open;
         set_cursor_lock(&mut window, cursor_lock_state.into());
     }
@@ -252,7 +268,7 @@ pub fn ui_menu(
                                 .num_columns(2)
                                 .striped(true)
                                 .show(ui, |ui| {
-                                    let mut mouse_sensitivity_changed: bool = false;
+                                    let mut control_settings_changed: bool = false;
                                     ui.horizontal_centered(|ui| {
                                         ui.label("Mouse Sensitivity");
                                         if ui
@@ -265,7 +281,7 @@ pub fn ui_menu(
                                             )
                                             .changed()
                                         {
-                                            mouse_sensitivity_changed = true;
+                                            control_settings_changed = true;
                                         };
                                     });
                                     ui.scope(|ui| {
@@ -281,10 +297,24 @@ pub fn ui_menu(
                                             )
                                             .changed()
                                         {
-                                            mouse_sensitivity_changed = true;
+                                            control_settings_changed = true;
                                         };
                                     });
-                                    if mouse_sensitivity_changed {
+                                    ui.end_row();
+
+                                    ui.label("Undo History Depth");
+                                    if ui
+                                        .add(
+                                            egui::DragValue::new(
+                                                &mut control_settings.undo_history_depth,
+                                            )
+                                            .clamp_range(1..=1000),
+                                        )
+                                        .changed()
+                                    {
+                                        control_settings_changed = true;
+                                    };
+                                    if control_settings_changed {
                                         export_settings(
                                             &mut *control_settings,
                                             "settings.control",
@@ -294,7 +324,78 @@ pub fn ui_menu(
                                 });
                         }
                         SettingsTabOption::Debug => {
-                            ui.label("Nothing here yet :)");
+                            let target = crosshair_target.ship.and_then(|ship| {
+                                ship_mass_query.get(ship).ok().map(|mass| (ship, mass))
+                            });
+                            let Some((ship, mass_properties)) = target else {
+                                ui.label("Look at a ship to see its properties");
+                                return;
+                            };
+                            Grid::new("Debug Info")
+                                .num_columns(2)
+                                .striped(true)
+                                .show(ui, |ui| {
+                                    ui.label("Mass");
+                                    ui.label(format!("{:.1} kg", mass_properties.mass));
+                                    ui.end_row();
+
+                                    ui.label("Center of Mass");
+                                    ui.label(format_vec3(mass_properties.local_center_of_mass));
+                                    ui.end_row();
+
+                                    let point = origin.absolute_position(crosshair_target.point);
+                                    ui.label("Crosshair Position");
+                                    ui.label(format!(
+                                        "{:.2}, {:.2}, {:.2}",
+                                        point.x, point.y, point.z
+                                    ));
+                                    ui.end_row();
+
+                                    if let Ok(power) = ship_power_query.get(ship) {
+                                        ui.label("Power");
+                                        ui.label(format!(
+                                            "{:.0} / {:.0} W supplied, {:.0} W generated",
+                                            power.supplied, power.demand, power.generation
+                                        ));
+                                        ui.end_row();
+
+                                        ui.label("Stored Energy");
+                                        ui.label(format!(
+                                            "{:.0} / {:.0} J",
+                                            power.stored, power.capacity
+                                        ));
+                                        ui.end_row();
+                                    }
+
+                                    if let Ok(trajectory) = trajectory_query.get(ship) {
+                                        for approach in trajectory.closest_approaches.iter() {
+                                            ui.label("Closest Approach");
+                                            ui.label(format!(
+                                                "{:.0} m in {:.0} s",
+                                                approach.distance, approach.time
+                                            ));
+                                            ui.end_row();
+                                        }
+
+                                        ui.label("Impact");
+                                        ui.label(match &trajectory.impact {
+                                            Some(impact) => format!("in {:.0} s", impact.time),
+                                            None => "None".to_string(),
+                                        });
+                                        ui.end_row();
+                                    }
+
+                                    let inertia = mass_properties.inertia;
+                                    for (index, row) in
+                                        [inertia.row(0), inertia.row(1), inertia.row(2)]
+                                            .iter()
+                                            .enumerate()
+                                    {
+                                        ui.label(if index == 0 { "Inertia Tensor" } else { "" });
+                                        ui.label(format_vec3(*row));
+                                        ui.end_row();
+                                    }
+                                });
                         }
                     };
                 });